@@ -0,0 +1,148 @@
+//! Detection of flattened cells that nobody is likely to have designed,
+//! either because they are extreme or because many factors stacked up.
+
+use std::io::Write;
+use std::path::Path;
+
+use crate::combine::Factor;
+use crate::report::{format_modifier, ReportArgs};
+use crate::{collect_factors, UnitBalance, UnitObjmaskMap};
+
+#[derive(Clone, Copy, Debug)]
+pub struct AnomalyThresholds {
+    /// Flattened cells below this are reported.
+    pub min: f32,
+    /// Flattened cells above this are reported.
+    pub max: f32,
+    /// Cells combined from more than this many non-100 factors are
+    /// reported.
+    pub max_factors: usize,
+}
+
+impl Default for AnomalyThresholds {
+    fn default() -> AnomalyThresholds {
+        AnomalyThresholds {
+            min: 20.0,
+            max: 500.0,
+            max_factors: 3,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+struct Anomaly {
+    unit_a: String,
+    unit_b: String,
+    modifier: f32,
+    reasons: Vec<String>,
+    /// The non-100 cells that contributed, as attacker, target and
+    /// modifier.
+    factors: Vec<(String, String, f32)>,
+}
+
+/// Check every flattened unit pair in `new_unit_balance` against the
+/// thresholds, using `old_unit_balance` to find the factors behind it.
+fn find_anomalies(unit_objmask_map: &UnitObjmaskMap, old_unit_balance: &UnitBalance,
+                  new_unit_balance: &UnitBalance, thresholds: &AnomalyThresholds) -> Vec<Anomaly> {
+    let mut anomalies = Vec::new();
+    let mut factors = Vec::new();
+
+    for (unit_a, unit_a_objmask) in unit_objmask_map.iter() {
+        let new_entry = match new_unit_balance.entries.get(unit_a) {
+            Some(entry) => entry,
+            None => continue,
+        };
+
+        for (unit_b, unit_b_objmask) in unit_objmask_map.iter() {
+            let modifier = match new_entry.modifiers.get(unit_b) {
+                Some(&modifier) => modifier,
+                None => continue,
+            };
+
+            collect_factors(unit_a, unit_a_objmask, unit_b, unit_b_objmask, old_unit_balance, &mut factors);
+            factors.retain(|factor: &Factor| factor.modifier != 100.0);
+
+            let mut reasons = Vec::new();
+            if modifier < thresholds.min {
+                reasons.push(format!("below {}", thresholds.min));
+            }
+            if modifier > thresholds.max {
+                reasons.push(format!("above {}", thresholds.max));
+            }
+            if factors.len() > thresholds.max_factors {
+                reasons.push(format!("{} factors stacked", factors.len()));
+            }
+
+            if !reasons.is_empty() {
+                anomalies.push(Anomaly {
+                    unit_a: unit_a.clone(),
+                    unit_b: unit_b.clone(),
+                    modifier,
+                    reasons,
+                    factors: factors.iter()
+                        .map(|factor| (factor.attacker.to_owned(), factor.target.to_owned(), factor.modifier))
+                        .collect(),
+                });
+            }
+        }
+    }
+
+    anomalies
+}
+
+pub fn run_anomalies(args: &[String]) -> Result<(), String> {
+    let usage = "Usage: anomalies [--unitrules <file>] [--combine <mode>] [--min <value>] [--max <value>] \
+                 [--max-factors <count>] [--ci] <balance file>";
+
+    let mut thresholds = AnomalyThresholds::default();
+    let mut ci = false;
+    let args = ReportArgs::parse(args, &["--combine"], |arg, value| {
+        match arg {
+            "--min" => thresholds.min = parse_number(value()?)?,
+            "--max" => thresholds.max = parse_number(value()?)?,
+            "--max-factors" => thresholds.max_factors = parse_number(value()?)?,
+            "--ci" => ci = true,
+            _ => return Ok(false),
+        }
+
+        Ok(true)
+    })?;
+
+    let balance_xml_path = match args.positional.as_slice() {
+        [balance] => Path::new(balance),
+        _ => return Err(usage.to_owned()),
+    };
+
+    let (unit_objmask_map, old_unit_balance, new_unit_balance) = args.read_flattened(balance_xml_path)?;
+    let anomalies = find_anomalies(&unit_objmask_map, &old_unit_balance, &new_unit_balance, &thresholds);
+
+    write_anomalies(&mut std::io::stdout(), &anomalies)
+        .map_err(|e| format!("Failed to write anomalies: {}", e))?;
+
+    if anomalies.is_empty() {
+        eprintln!("No anomalous cells found");
+    } else if ci {
+        return Err(format!("{} anomalous cells found", anomalies.len()));
+    } else {
+        eprintln!("Warning: {} anomalous cells found", anomalies.len());
+    }
+
+    Ok(())
+}
+
+fn write_anomalies(writer: &mut dyn Write, anomalies: &[Anomaly]) -> std::io::Result<()> {
+    for anomaly in anomalies {
+        writeln!(writer, "{} vs {}: {} ({})", anomaly.unit_a, anomaly.unit_b, format_modifier(anomaly.modifier),
+                 anomaly.reasons.join(", "))?;
+        for (attacker, target, modifier) in &anomaly.factors {
+            writeln!(writer, "    {} vs {}: {}", attacker, target, format_modifier(*modifier))?;
+        }
+    }
+
+    Ok(())
+}
+
+fn parse_number<T: std::str::FromStr>(value: &str) -> Result<T, String>
+    where T::Err: std::fmt::Display {
+    value.parse().map_err(|e| format!("Failed to parse \"{}\": {}", value, e))
+}
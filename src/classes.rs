@@ -0,0 +1,86 @@
+//! Grouping of units that the balance table cannot tell apart.
+
+use std::io::Write;
+use std::path::Path;
+
+use crate::report::ReportArgs;
+use crate::{objmask_to_string, units, FnvIndexMap, UnitBalance, UnitObjmaskMap};
+
+/// Group units by their objmask, as flag character codes.
+fn classes_by_objmask(unit_objmask_map: &UnitObjmaskMap) -> FnvIndexMap<String, Vec<&str>> {
+    let mut classes: FnvIndexMap<String, Vec<&str>> = FnvIndexMap::default();
+    for unit in units(unit_objmask_map) {
+        let objmask = &unit_objmask_map[unit];
+        classes.entry(objmask_to_string(objmask)).or_default().push(unit);
+    }
+
+    classes
+}
+
+/// Group units whose flattened rows and columns are identical, to two
+/// decimal places.
+fn classes_by_balance<'a>(unit_objmask_map: &'a UnitObjmaskMap,
+                          new_unit_balance: &UnitBalance) -> Vec<Vec<&'a str>> {
+    let cell = |unit_a: &str, unit_b: &str| {
+        new_unit_balance.entries.get(unit_a)
+            .and_then(|entry| entry.modifiers.get(unit_b))
+            .map(|&modifier| (modifier * 100.0).round() as i64)
+    };
+
+    let mut classes: FnvIndexMap<Vec<Option<i64>>, Vec<&str>> = FnvIndexMap::default();
+    for unit in units(unit_objmask_map) {
+        let row = unit_objmask_map.keys().map(|other| cell(unit, other));
+        let column = unit_objmask_map.keys().map(|other| cell(other, unit));
+        classes.entry(row.chain(column).collect()).or_default().push(unit);
+    }
+
+    classes.into_iter().map(|(_, units)| units).collect()
+}
+
+pub fn run_classes(args: &[String]) -> Result<(), String> {
+    let usage = "Usage: classes [--unitrules <file>] [--combine <mode>] <balance file>";
+
+    let args = ReportArgs::parse(args, &["--combine"], |_, _| Ok(false))?;
+    let balance_xml_path = match args.positional.as_slice() {
+        [balance] => Path::new(balance),
+        _ => return Err(usage.to_owned()),
+    };
+
+    let (unit_objmask_map, _, new_unit_balance) = args.read_flattened(balance_xml_path)?;
+
+    write_classes(&mut std::io::stdout(), &unit_objmask_map, &new_unit_balance)
+        .map_err(|e| format!("Failed to write classes: {}", e))
+}
+
+fn write_classes(writer: &mut dyn Write, unit_objmask_map: &UnitObjmaskMap,
+                 new_unit_balance: &UnitBalance) -> std::io::Result<()> {
+    writeln!(writer, "Units sharing an OBJ_MASK:")?;
+    let mut shared = 0;
+    for (objmask, units) in classes_by_objmask(unit_objmask_map) {
+        if units.len() > 1 {
+            writeln!(writer, "    {}: {}", if objmask.is_empty() { "(none)" } else { &objmask }, units.join(", "))?;
+            shared += 1;
+        }
+    }
+    if shared == 0 {
+        writeln!(writer, "    None")?;
+    }
+
+    writeln!(writer)?;
+    writeln!(writer, "Units with identical flattened rows and columns:")?;
+    let mut identical = 0;
+    for units in classes_by_balance(unit_objmask_map, new_unit_balance) {
+        if units.len() > 1 {
+            let objmasks: Vec<String> = units.iter()
+                .map(|unit| format!("{} ({})", unit, objmask_to_string(&unit_objmask_map[*unit])))
+                .collect();
+            writeln!(writer, "    {}", objmasks.join(", "))?;
+            identical += 1;
+        }
+    }
+    if identical == 0 {
+        writeln!(writer, "    None")?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,104 @@
+//! Strategies for combining the balance modifiers that apply to a unit
+//! pair into a single flattened value.
+
+use std::fmt::Debug;
+
+/// A balance cell that applies to a unit pair, either directly through
+/// the unit names or through one of their objmask flags.
+#[derive(Clone, Copy, Debug)]
+pub struct Factor<'a> {
+    /// Name of the balance entry, either the attacking unit or one of its
+    /// objmask flags.
+    pub attacker: &'a str,
+    /// Name of the modifier within the entry, either the target unit or
+    /// one of its objmask flags.
+    pub target: &'a str,
+    pub modifier: f32,
+    /// Whether both the attacker and target are the units themselves
+    /// rather than objmask flags.
+    pub direct: bool,
+}
+
+pub trait Combiner: Debug + Sync {
+    /// Name used to select the combiner on the command line.
+    fn name(&self) -> &'static str;
+
+    /// Combine the factors that apply to a unit pair. An empty slice
+    /// means no cell applies, which should give 100.
+    fn combine(&self, factors: &[Factor]) -> f32;
+}
+
+/// Multiply every factor together. This is the default.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Multiplicative;
+
+/// Add up the percentage points by which each factor differs from 100.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Additive;
+
+/// Apply only the factor furthest from 100.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Strongest;
+
+/// Use the direct unit vs unit cell if there is one, otherwise multiply
+/// the objmask factors.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DirectOverride;
+
+pub const COMBINERS: [&dyn Combiner; 4] = [&Multiplicative, &Additive, &Strongest, &DirectOverride];
+
+impl Combiner for Multiplicative {
+    fn name(&self) -> &'static str {
+        "multiply"
+    }
+
+    fn combine(&self, factors: &[Factor]) -> f32 {
+        factors.iter().fold(100.0, |balance, factor| balance * factor.modifier / 100.0)
+    }
+}
+
+impl Combiner for Additive {
+    fn name(&self) -> &'static str {
+        "add"
+    }
+
+    fn combine(&self, factors: &[Factor]) -> f32 {
+        let balance = factors.iter().fold(100.0, |balance, factor| balance + factor.modifier - 100.0);
+        balance.max(0.0)
+    }
+}
+
+impl Combiner for Strongest {
+    fn name(&self) -> &'static str {
+        "strongest"
+    }
+
+    fn combine(&self, factors: &[Factor]) -> f32 {
+        factors.iter()
+            .map(|factor| factor.modifier)
+            .fold(100.0, |strongest: f32, modifier| {
+                if (modifier - 100.0).abs() > (strongest - 100.0).abs() {
+                    modifier
+                } else {
+                    strongest
+                }
+            })
+    }
+}
+
+impl Combiner for DirectOverride {
+    fn name(&self) -> &'static str {
+        "direct"
+    }
+
+    fn combine(&self, factors: &[Factor]) -> f32 {
+        match factors.iter().find(|factor| factor.direct) {
+            Some(factor) => factor.modifier,
+            None => Multiplicative.combine(factors),
+        }
+    }
+}
+
+pub fn combiner_by_name(name: &str) -> Option<&'static dyn Combiner> {
+    COMBINERS.iter().find(|combiner| combiner.name() == name).cloned()
+}
@@ -0,0 +1,211 @@
+//! Conversion of balance and unit data between XML and the serde
+//! supported formats (JSON, TOML and RON).
+
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+use fnv::FnvHashSet;
+
+use serde::de::Error as _;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::{
+    objmask_name_to_attrib_str, parse_balance_document, parse_unitrules, write_exact_balance, BalanceDocument,
+    FnvIndexMap, MergePolicy, UnitBalance, UnitObjmaskMap, OBJMASK_INFO,
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Format {
+    Xml,
+    Json,
+    Toml,
+    Ron,
+}
+
+impl Format {
+    fn from_path(path: &Path) -> Result<Format, String> {
+        let extension = path.extension()
+            .and_then(|ext| ext.to_str())
+            .map(|ext| ext.to_ascii_lowercase());
+
+        match extension.as_deref() {
+            Some("xml") => Ok(Format::Xml),
+            Some("json") => Ok(Format::Json),
+            Some("toml") => Ok(Format::Toml),
+            Some("ron") => Ok(Format::Ron),
+            _ => Err(format!("Unable to determine the format of \"{}\" from its extension", path.display())),
+        }
+    }
+}
+
+/// Wrapper giving the unit→objmask map a serde representation. Flags
+/// are written in `OBJMASK_INFO` order and may be read back either by
+/// full attribute name or by their single character code.
+#[derive(Serialize, Deserialize)]
+#[serde(transparent)]
+struct UnitObjmasks(#[serde(serialize_with = "serialize_objmask_map", deserialize_with = "deserialize_objmask_map")]
+                    UnitObjmaskMap);
+
+fn serialize_objmask_map<S: Serializer>(map: &UnitObjmaskMap, serializer: S) -> Result<S::Ok, S::Error> {
+    let sorted: FnvIndexMap<&str, Vec<&'static str>> = map.iter()
+        .map(|(unit, objmask)| {
+            let flags = OBJMASK_INFO.iter()
+                .map(|&(_, attrib)| attrib)
+                .filter(|attrib| objmask.contains(attrib))
+                .collect();
+            (unit.as_str(), flags)
+        })
+        .collect();
+
+    sorted.serialize(serializer)
+}
+
+fn deserialize_objmask_map<'de, D: Deserializer<'de>>(deserializer: D) -> Result<UnitObjmaskMap, D::Error> {
+    let raw = FnvIndexMap::<String, Vec<String>>::deserialize(deserializer)?;
+
+    let mut map = UnitObjmaskMap::default();
+    for (unit, flags) in raw {
+        let mut objmask = FnvHashSet::default();
+        for flag in flags {
+            let attrib = objmask_name_to_attrib_str(&flag)
+                .ok_or_else(|| D::Error::custom(format!("unknown OBJ_MASK flag \"{}\" for unit \"{}\"", flag, unit)))?;
+            objmask.insert(attrib);
+        }
+        map.insert(unit, objmask);
+    }
+
+    Ok(map)
+}
+
+pub fn run_convert(args: &[String]) -> Result<(), String> {
+    let mut units = false;
+    let mut paths = Vec::new();
+    for arg in args {
+        match arg.as_str() {
+            "--units" => units = true,
+            _ => paths.push(arg.as_str()),
+        }
+    }
+
+    let (input_path, output_path) = match paths.as_slice() {
+        [input, output] => (Path::new(input), Path::new(output)),
+        _ => return Err("Usage: convert [--units] <input> <output>".to_owned()),
+    };
+
+    let input_format = Format::from_path(input_path)?;
+    let output_format = if output_path == Path::new("-") {
+        Format::Json
+    } else {
+        Format::from_path(output_path)?
+    };
+
+    if units {
+        let unit_objmask_map = match input_format {
+            Format::Xml => parse_unitrules(input_path)?,
+            _ => read_serialized::<UnitObjmasks>(input_path, input_format)?.0,
+        };
+
+        if output_format == Format::Xml {
+            return Err("Writing the unit OBJ_MASK map as XML is not supported".to_owned());
+        }
+
+        let mut output = create_output(output_path)?;
+        write_serialized(&mut output, &UnitObjmasks(unit_objmask_map), output_format)?;
+        flush_output(output, output_path)
+    } else {
+        let unit_balance = match input_format {
+            Format::Xml => {
+                let (document, _) = parse_balance_document(input_path, MergePolicy::default())?;
+                for warning in discarded_content(&document, input_path, output_format) {
+                    eprintln!("Warning: {}", warning);
+                }
+                document.tables.into_iter().next().map(|table| table.unit_balance).unwrap_or_default()
+            }
+            _ => read_serialized::<UnitBalance>(input_path, input_format)?,
+        };
+
+        let mut output = create_output(output_path)?;
+        match output_format {
+            Format::Xml => write_exact_balance(&mut output, &unit_balance)
+                .map_err(|e| format!("Failed to write balance.xml: {}", e))?,
+            _ => write_serialized(&mut output, &unit_balance, output_format)?,
+        }
+        flush_output(output, output_path)
+    }
+}
+
+/// Warnings for the parts of `document` read from `path` that are lost
+/// when only the cells of its first table are written as `output_format`.
+fn discarded_content(document: &BalanceDocument, path: &Path, output_format: Format) -> Vec<String> {
+    let mut warnings = Vec::new();
+    if document.tables.len() > 1 {
+        warnings.push(format!("Only the first of the {} tables in \"{}\" is converted", document.tables.len(),
+                              path.display()));
+    }
+    if !document.header.is_empty() || !document.prolog.is_empty() || !document.kept.is_empty()
+        || document.tables.first().is_some_and(|table| !table.kept.is_empty()) {
+        warnings.push(format!("Comments and other content outside the entries of \"{}\" are not converted",
+                              path.display()));
+    }
+
+    // ENTRY elements keep their other content when written back as XML.
+    if output_format != Format::Xml {
+        let entries: Vec<_> = document.tables.first()
+            .map(|table| table.unit_balance.entries.values().collect())
+            .unwrap_or_default();
+        if entries.iter().any(|entry| !entry.passthrough.is_empty()) {
+            warnings.push(format!("Attributes that are not numbers in \"{}\" are not converted", path.display()));
+        }
+        if entries.iter().any(|entry| !entry.children.trim().is_empty()) {
+            warnings.push(format!("The content of ENTRY elements in \"{}\" is not converted", path.display()));
+        }
+    }
+
+    warnings
+}
+
+fn create_output(path: &Path) -> Result<Box<dyn Write>, String> {
+    if path == Path::new("-") {
+        return Ok(Box::new(std::io::stdout()));
+    }
+
+    let file = File::create(path)
+        .map_err(|e| format!("Failed to create \"{}\": {}", path.display(), e))?;
+    Ok(Box::new(BufWriter::new(file)))
+}
+
+fn flush_output(mut output: Box<dyn Write>, path: &Path) -> Result<(), String> {
+    output.flush()
+        .map_err(|e| format!("Failed to write \"{}\": {}", path.display(), e))
+}
+
+fn read_serialized<T: for<'de> Deserialize<'de>>(path: &Path, format: Format) -> Result<T, String> {
+    let file = File::open(path)
+        .map_err(|e| format!("Failed to open \"{}\": {}", path.display(), e))?;
+    let mut contents = String::new();
+    BufReader::new(file).read_to_string(&mut contents)
+        .map_err(|e| format!("Failed to read \"{}\": {}", path.display(), e))?;
+
+    eprintln!("Processing {}", path.display());
+
+    match format {
+        Format::Json => serde_json::from_str(&contents).map_err(|e| e.to_string()),
+        Format::Toml => toml::from_str(&contents).map_err(|e| e.to_string()),
+        Format::Ron => ron::de::from_str(&contents).map_err(|e| e.to_string()),
+        Format::Xml => unreachable!(),
+    }.map_err(|e| format!("Failed to parse \"{}\": {}", path.display(), e))
+}
+
+fn write_serialized<T: Serialize>(writer: &mut dyn Write, value: &T, format: Format) -> Result<(), String> {
+    let contents = match format {
+        Format::Json => serde_json::to_string_pretty(value).map_err(|e| e.to_string()),
+        Format::Toml => toml::to_string(value).map_err(|e| e.to_string()),
+        Format::Ron => ron::ser::to_string_pretty(value, Default::default()).map_err(|e| e.to_string()),
+        Format::Xml => unreachable!(),
+    }.map_err(|e| format!("Failed to serialise output: {}", e))?;
+
+    writer.write_all(contents.as_bytes())
+        .and_then(|_| writer.write_all(b"\n"))
+        .map_err(|e| format!("Failed to write output: {}", e))
+}
@@ -0,0 +1,96 @@
+//! Report of the units a given unit is strongest and weakest against, and
+//! the units strongest and weakest against it.
+
+use std::io::Write;
+use std::path::Path;
+
+use crate::report::{format_modifier, ReportArgs, Table};
+use crate::{collect_factors, units, UnitBalance, UnitObjmaskMap};
+
+/// A flattened cell between the reported unit and another unit.
+struct Matchup<'a> {
+    other: &'a str,
+    modifier: f32,
+    /// The non-100 cells it was combined from.
+    factors: String,
+}
+
+/// Collect the flattened cells for `unit` against every other unit, as
+/// the attacker if `attacking` and as the target otherwise.
+fn matchups<'a>(unit_objmask_map: &'a UnitObjmaskMap, old_unit_balance: &UnitBalance,
+                new_unit_balance: &UnitBalance, unit: &str, attacking: bool) -> Vec<Matchup<'a>> {
+    let mut matchups = Vec::new();
+    let mut factors = Vec::new();
+
+    for other in units(unit_objmask_map).filter(|other| *other != unit) {
+        let (unit_a, unit_b) = if attacking { (unit, other.as_str()) } else { (other.as_str(), unit) };
+        let modifier = match new_unit_balance.entries.get(unit_a).and_then(|entry| entry.modifiers.get(unit_b)) {
+            Some(&modifier) => modifier,
+            None => continue,
+        };
+
+        collect_factors(unit_a, &unit_objmask_map[unit_a], unit_b, &unit_objmask_map[unit_b], old_unit_balance,
+                        &mut factors);
+        let factors = factors.iter()
+            .filter(|factor| factor.modifier != 100.0)
+            .map(|factor| format!("{} vs {}: {}", factor.attacker, factor.target, format_modifier(factor.modifier)))
+            .collect::<Vec<_>>()
+            .join(", ");
+
+        matchups.push(Matchup { other, modifier, factors });
+    }
+
+    matchups.sort_by(|a, b| b.modifier.total_cmp(&a.modifier));
+    matchups
+}
+
+pub fn run_counters(args: &[String]) -> Result<(), String> {
+    let usage = "Usage: counters [--unitrules <file>] [--combine <mode>] [--top <count>] <balance file> <unit>";
+
+    let args = ReportArgs::parse(args, &["--combine", "--top"], |_, _| Ok(false))?;
+    let (balance_xml_path, unit) = match args.positional.as_slice() {
+        [balance, unit] => (Path::new(balance), *unit),
+        _ => return Err(usage.to_owned()),
+    };
+
+    let (unit_objmask_map, old_unit_balance, new_unit_balance) = args.read_flattened(balance_xml_path)?;
+    if !unit_objmask_map.contains_key(unit) {
+        return Err(format!("Unit \"{}\" not found in unitrules.xml", unit));
+    }
+
+    let targets = matchups(&unit_objmask_map, &old_unit_balance, &new_unit_balance, unit, true);
+    let attackers = matchups(&unit_objmask_map, &old_unit_balance, &new_unit_balance, unit, false);
+
+    write_counters(&mut std::io::stdout(), unit, &targets, &attackers, args.top)
+        .map_err(|e| format!("Failed to write counters: {}", e))
+}
+
+fn write_counters(writer: &mut dyn Write, unit: &str, targets: &[Matchup], attackers: &[Matchup],
+                  top: usize) -> std::io::Result<()> {
+    let sections = [
+        (format!("{} is strongest against", unit), "Target", targets, false),
+        (format!("{} is weakest against", unit), "Target", targets, true),
+        (format!("Strongest against {}", unit), "Attacker", attackers, false),
+        (format!("Weakest against {}", unit), "Attacker", attackers, true),
+    ];
+
+    for (i, (heading, column, matchups, weakest)) in sections.iter().enumerate() {
+        if i > 0 {
+            writeln!(writer)?;
+        }
+        writeln!(writer, "{}:", heading)?;
+
+        let mut table = Table::new(&[column, "Modifier", "Factors"]);
+        let mut add_row = |matchup: &Matchup| {
+            table.add_row(vec![matchup.other.to_owned(), format_modifier(matchup.modifier), matchup.factors.clone()]);
+        };
+        if *weakest {
+            matchups.iter().rev().take(top).for_each(&mut add_row);
+        } else {
+            matchups.iter().take(top).for_each(&mut add_row);
+        }
+        table.write(writer)?;
+    }
+
+    Ok(())
+}
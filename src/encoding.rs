@@ -0,0 +1,154 @@
+//! Detection and conversion of the text encoding of XML files.
+//!
+//! Modding tools save balance.xml, unitrules.xml, patches and overrides
+//! files as UTF-8, UTF-16 with a byte order mark or Windows-1252. Files are decoded to UTF-8 before they
+//! are parsed, and output can be written in any of these encodings.
+
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::Path;
+
+use encoding_rs::{Encoding, UTF_16BE, UTF_16LE, UTF_8, WINDOWS_1252};
+
+pub const UTF_8_BOM: &[u8] = b"\xEF\xBB\xBF";
+
+/// Read and decode an XML file, or standard input if `path` is `-`,
+/// returning its text and the encoding it was in. `file_name` is used in
+/// messages.
+pub fn read_xml_file(path: &Path, file_name: &str) -> Result<(String, &'static Encoding), String> {
+    let bytes = read_file(path, file_name)?;
+    decode_xml(&bytes, file_name)
+}
+
+/// Read a file, or standard input if `path` is `-`.
+pub fn read_file(path: &Path, file_name: &str) -> Result<Vec<u8>, String> {
+    let mut bytes = Vec::new();
+    if path == Path::new("-") {
+        std::io::stdin().read_to_end(&mut bytes)
+            .map_err(|e| format!("Failed to read {} from standard input: {}", file_name, e))?;
+    } else {
+        let mut file = File::open(path)
+            .map_err(|e| format!("Failed to open {}: {}", file_name, e))?;
+        file.read_to_end(&mut bytes)
+            .map_err(|e| format!("Failed to read {}: {}", file_name, e))?;
+    }
+
+    Ok(bytes)
+}
+
+/// Decode an XML document, choosing the encoding from its byte order mark,
+/// the layout of its first characters or its XML declaration, in that
+/// order. Undeclared text that is not valid UTF-8 is read as
+/// Windows-1252.
+pub fn decode_xml(bytes: &[u8], file_name: &str) -> Result<(String, &'static Encoding), String> {
+    let (encoding, bom_len) = match Encoding::for_bom(bytes) {
+        Some(bom) => bom,
+        None => match bytes {
+            [b'<', 0, ..] => (UTF_16LE, 0),
+            [0, b'<', ..] => (UTF_16BE, 0),
+            _ => match declared_encoding(bytes) {
+                Some(encoding) if encoding == UTF_16LE || encoding == UTF_16BE => {
+                    eprintln!("Warning: {} declares UTF-16 but is not, reading it as UTF-8", file_name);
+                    (UTF_8, 0)
+                }
+                Some(encoding) => (encoding, 0),
+                None if std::str::from_utf8(bytes).is_err() => {
+                    eprintln!("Warning: {} is not valid UTF-8, reading it as Windows-1252", file_name);
+                    (WINDOWS_1252, 0)
+                }
+                None => (UTF_8, 0),
+            },
+        },
+    };
+
+    let text = encoding.decode_without_bom_handling_and_without_replacement(&bytes[bom_len..])
+        .ok_or_else(|| format!("Failed to decode {} as {}", file_name, encoding.name()))?;
+
+    Ok((text.into_owned(), encoding))
+}
+
+/// Decode a text file other than XML, choosing the encoding from its byte
+/// order mark. Text without one that is not valid UTF-8 is read as
+/// Windows-1252.
+pub fn decode_text(bytes: &[u8], file_name: &str) -> Result<String, String> {
+    let (encoding, bom_len) = match Encoding::for_bom(bytes) {
+        Some(bom) => bom,
+        None if std::str::from_utf8(bytes).is_err() => {
+            eprintln!("Warning: {} is not valid UTF-8, reading it as Windows-1252", file_name);
+            (WINDOWS_1252, 0)
+        }
+        None => (UTF_8, 0),
+    };
+
+    encoding.decode_without_bom_handling_and_without_replacement(&bytes[bom_len..])
+        .map(|text| text.into_owned())
+        .ok_or_else(|| format!("Failed to decode {} as {}", file_name, encoding.name()))
+}
+
+/// Whether a document starts with a UTF-8 byte order mark, which is kept
+/// when it is written back out as UTF-8.
+pub fn has_utf8_bom(bytes: &[u8]) -> bool {
+    bytes.starts_with(UTF_8_BOM)
+}
+
+/// Find the encoding named by the XML declaration at the start of a
+/// document in an ASCII compatible encoding.
+fn declared_encoding(bytes: &[u8]) -> Option<&'static Encoding> {
+    let bytes = bytes.strip_prefix(b"<?xml")?;
+    let declaration = &bytes[..bytes.windows(2).position(|window| window == b"?>")?];
+    let start = declaration.windows(8).position(|window| window == b"encoding")? + 8;
+
+    let rest = declaration[start..].trim_ascii_start().strip_prefix(b"=")?.trim_ascii_start();
+    let (&quote, rest) = rest.split_first()?;
+    if quote != b'"' && quote != b'\'' {
+        return None;
+    }
+    let label = &rest[..rest.iter().position(|&c| c == quote)?];
+
+    Encoding::for_label(label)
+}
+
+/// Look up an output encoding by label, such as "utf-8", "utf-16le",
+/// "utf-16be" or "windows-1252".
+pub fn encoding_for_label(label: &str) -> Result<&'static Encoding, String> {
+    Encoding::for_label(label.as_bytes())
+        .filter(|encoding| encoding.output_encoding() == *encoding || encoding.name().starts_with("UTF-16"))
+        .ok_or_else(|| format!("Unknown or unsupported output encoding \"{}\"", label))
+}
+
+/// The encoding name to give in the XML declaration of output in
+/// `encoding`.
+pub fn declaration_name(encoding: &'static Encoding) -> &'static str {
+    if encoding == UTF_16LE || encoding == UTF_16BE {
+        "UTF-16"
+    } else {
+        encoding.name()
+    }
+}
+
+/// Write UTF-8 `text` in `encoding`. UTF-16 output starts with a byte
+/// order mark. Characters that `encoding` cannot represent are an error.
+pub fn write_encoded(writer: &mut dyn Write, text: &str, encoding: &'static Encoding) -> Result<(), String> {
+    let write_error = |e: std::io::Error| format!("Failed to write output: {}", e);
+
+    if encoding == UTF_8 {
+        writer.write_all(text.as_bytes()).map_err(write_error)
+    } else if encoding == UTF_16LE || encoding == UTF_16BE {
+        // encoding_rs only decodes UTF-16, so encode it by hand.
+        let mut bytes = Vec::with_capacity(2 + text.len() * 2);
+        for unit in std::iter::once(0xFEFF).chain(text.encode_utf16()) {
+            if encoding == UTF_16LE {
+                bytes.extend_from_slice(&unit.to_le_bytes());
+            } else {
+                bytes.extend_from_slice(&unit.to_be_bytes());
+            }
+        }
+        writer.write_all(&bytes).map_err(write_error)
+    } else {
+        let (bytes, _, unmappable) = encoding.encode(text);
+        if unmappable {
+            return Err(format!("The output contains characters that cannot be written as {}", encoding.name()));
+        }
+        writer.write_all(&bytes).map_err(write_error)
+    }
+}
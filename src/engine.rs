@@ -0,0 +1,146 @@
+//! Emulation of the balance modifiers the game actually applies, for
+//! comparison with the intended objmask semantics.
+
+use std::path::Path;
+
+use crate::combine::{self, Combiner, Factor};
+use crate::report::{format_modifier, ReportArgs, Table};
+use crate::{collect_factors, objmask_name_to_attrib_str, UnitBalance, UnitObjmaskMap};
+
+/// Model of which balance cells the game applies.
+#[derive(Clone, Copy, Debug)]
+pub struct EngineModel {
+    /// Whether entries named after an objmask flag are applied to the
+    /// attacking unit.
+    pub objmask_rows: bool,
+    /// Whether modifiers named after an objmask flag are applied to the
+    /// target unit.
+    pub objmask_columns: bool,
+}
+
+impl Default for EngineModel {
+    /// The buggy engine, which ignores objmasks entirely.
+    fn default() -> EngineModel {
+        EngineModel {
+            objmask_rows: false,
+            objmask_columns: false,
+        }
+    }
+}
+
+impl EngineModel {
+    fn from_name(name: &str) -> Option<EngineModel> {
+        let (objmask_rows, objmask_columns) = match name {
+            "ignore-objmasks" => (false, false),
+            "ignore-rows" => (false, true),
+            "ignore-columns" => (true, false),
+            "correct" => (true, true),
+            _ => return None,
+        };
+
+        Some(EngineModel { objmask_rows, objmask_columns })
+    }
+
+    fn applies(&self, factor: &Factor) -> bool {
+        (self.objmask_rows || objmask_name_to_attrib_str(factor.attacker).is_none())
+            && (self.objmask_columns || objmask_name_to_attrib_str(factor.target).is_none())
+    }
+}
+
+/// The intended and engine-applied modifiers for a unit pair.
+#[derive(Clone, Copy, Debug)]
+pub struct Evaluation {
+    pub intended: f32,
+    pub engine: f32,
+}
+
+pub struct Evaluator<'a> {
+    unit_objmask_map: &'a UnitObjmaskMap,
+    unit_balance: &'a UnitBalance,
+    model: EngineModel,
+    combiner: &'static dyn Combiner,
+}
+
+impl<'a> Evaluator<'a> {
+    pub fn new(unit_objmask_map: &'a UnitObjmaskMap, unit_balance: &'a UnitBalance, model: EngineModel,
+               combiner: &'static dyn Combiner) -> Evaluator<'a> {
+        Evaluator { unit_objmask_map, unit_balance, model, combiner }
+    }
+
+    /// Evaluate unit A attacking unit B, or `None` if either unit is not
+    /// in unitrules.xml.
+    pub fn evaluate(&self, unit_a: &str, unit_b: &str) -> Option<Evaluation> {
+        let (unit_a, unit_a_objmask) = self.unit_objmask_map.get_key_value(unit_a)?;
+        let (unit_b, unit_b_objmask) = self.unit_objmask_map.get_key_value(unit_b)?;
+
+        let mut factors = Vec::new();
+        collect_factors(unit_a, unit_a_objmask, unit_b, unit_b_objmask, self.unit_balance, &mut factors);
+        let intended = self.combiner.combine(&factors);
+
+        // The game multiplies whatever cells it does apply.
+        factors.retain(|factor| self.model.applies(factor));
+        let engine = combine::Multiplicative.combine(&factors);
+
+        Some(Evaluation { intended, engine })
+    }
+}
+
+pub fn run_evaluate(args: &[String]) -> Result<(), String> {
+    let usage = "Usage: evaluate [--unitrules <file>] [--engine <model>] [--combine <mode>] [--all] \
+                 <balance file> [<attacker> <target>]...";
+
+    let mut model = EngineModel::default();
+    let mut all = false;
+    let args = ReportArgs::parse(args, &["--combine"], |arg, value| {
+        match arg {
+            "--engine" => {
+                let name = value()?;
+                model = EngineModel::from_name(name)
+                    .ok_or_else(|| format!("Unknown engine model \"{}\"", name))?;
+            }
+            "--all" => all = true,
+            _ => return Ok(false),
+        }
+
+        Ok(true)
+    })?;
+
+    let (balance_xml_path, pairs) = match args.positional.split_first() {
+        Some((balance, pairs)) if pairs.len() % 2 == 0 && (all || !pairs.is_empty()) => (Path::new(balance), pairs),
+        _ => return Err(usage.to_owned()),
+    };
+
+    let (unit_objmask_map, unit_balance) = args.read_inputs(balance_xml_path)?;
+    let evaluator = Evaluator::new(&unit_objmask_map, &unit_balance, model, args.balance.combiner);
+
+    let mut table = Table::new(&["Attacker", "Target", "Intended", "Engine", "Difference"]);
+    let mut add_row = |unit_a: &str, unit_b: &str, evaluation: Evaluation| {
+        table.add_row(vec![
+            unit_a.to_owned(),
+            unit_b.to_owned(),
+            format_modifier(evaluation.intended),
+            format_modifier(evaluation.engine),
+            format_modifier(evaluation.engine - evaluation.intended),
+        ]);
+    };
+
+    for pair in pairs.chunks(2) {
+        let evaluation = evaluator.evaluate(pair[0], pair[1])
+            .ok_or_else(|| format!("Unit \"{}\" or \"{}\" not found in unitrules.xml", pair[0], pair[1]))?;
+        add_row(pair[0], pair[1], evaluation);
+    }
+
+    if all {
+        for unit_a in unit_objmask_map.keys() {
+            for unit_b in unit_objmask_map.keys() {
+                let evaluation = evaluator.evaluate(unit_a, unit_b).unwrap();
+                if evaluation.intended.round() != evaluation.engine.round() {
+                    add_row(unit_a, unit_b, evaluation);
+                }
+            }
+        }
+    }
+
+    table.write(&mut std::io::stdout())
+        .map_err(|e| format!("Failed to write evaluation: {}", e))
+}
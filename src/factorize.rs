@@ -0,0 +1,340 @@
+//! Recovery of an objmask-level source table from a flattened unit×unit
+//! balance table.
+//!
+//! Each flattened cell is modelled as the product of the factors
+//! `calculate_new_balance` would apply, so the fit works on the logarithm
+//! of the cell values where that product becomes a sum. Factors are
+//! chosen greedily: at every step the objmask row × objmask column factor
+//! that explains the most still unexplained cells is added, which keeps
+//! the result compact even when several flags always appear together.
+//! The same search is then repeated for unit row × objmask column (and
+//! the transposed) factors, and whatever remains is written back as
+//! per-unit overrides.
+
+use std::path::Path;
+
+use fnv::FnvHashMap;
+
+use crate::{
+    calculate_new_balance, parse_balance, parse_unitrules, sibling_unit_rules_path, write_exact_balance,
+    BalanceOptions, UnitBalance, OBJMASK_INFO,
+};
+
+/// Residuals within this distance in log space (roughly one percent) are
+/// considered explained, allowing for the rounding of flattened values.
+const RESIDUAL_TOLERANCE: f32 = 0.01;
+
+/// A factor must explain at least this many more cells than it disturbs
+/// before it is added.
+const MIN_GAIN: usize = 2;
+
+pub fn run_factorize(args: &[String]) -> Result<(), String> {
+    let (balance_xml_path, unit_rules_path) = match args {
+        [balance] => (Path::new(balance), sibling_unit_rules_path(Path::new(balance))?),
+        [balance, unitrules] => (Path::new(balance), Path::new(unitrules).to_owned()),
+        _ => return Err("Usage: factorize <balance file> [unitrules file]".to_owned()),
+    };
+
+    let unit_objmask_map = parse_unitrules(&unit_rules_path)?;
+    let flat_unit_balance = parse_balance(balance_xml_path)?;
+
+    eprintln!("Factorising balance table");
+
+    // Units present in both files, with their objmask flags as indices
+    // into `OBJMASK_INFO`.
+    let units: Vec<(&str, Vec<usize>)> = unit_objmask_map.iter()
+        .filter(|(unit, _)| flat_unit_balance.entries.contains_key(unit.as_str()))
+        .map(|(unit, objmask)| {
+            let flags = OBJMASK_INFO.iter()
+                .enumerate()
+                .filter(|(_, (_, attrib))| objmask.contains(attrib))
+                .map(|(i, _)| i)
+                .collect();
+            (unit.as_str(), flags)
+        })
+        .collect();
+
+    let n = units.len();
+    let cell = |a: usize, b: usize| a * n + b;
+
+    // Flattened values, and their logarithms where they can be fitted.
+    let mut values = vec![None; n * n];
+    let mut targets = vec![None; n * n];
+    for (a, &(unit_a, _)) in units.iter().enumerate() {
+        let entry = &flat_unit_balance.entries[unit_a];
+        for (b, &(unit_b, _)) in units.iter().enumerate() {
+            if let Some(&value) = entry.modifiers.get(unit_b) {
+                values[cell(a, b)] = Some(value);
+                if value > 0.0 {
+                    targets[cell(a, b)] = Some((value / 100.0).ln());
+                }
+            }
+        }
+    }
+
+    // Units sharing an objmask signature receive identical objmask
+    // factors, so the objmask-level search runs over pairs of signatures
+    // summarised by their median value.
+    let mut signature_index = FnvHashMap::default();
+    let mut signatures: Vec<(&[usize], Vec<usize>)> = Vec::new();
+    for (i, (_, flags)) in units.iter().enumerate() {
+        let index = *signature_index.entry(flags.as_slice()).or_insert_with(|| {
+            signatures.push((flags.as_slice(), Vec::new()));
+            signatures.len() - 1
+        });
+        signatures[index].1.push(i);
+    }
+
+    let mut class_targets = Vec::new();
+    let mut class_weights = Vec::new();
+    let mut candidates = vec![Vec::new(); OBJMASK_INFO.len() * OBJMASK_INFO.len()];
+    let mut samples = Vec::new();
+    for (flags_p, units_p) in &signatures {
+        for (flags_q, units_q) in &signatures {
+            samples.clear();
+            for &a in units_p {
+                samples.extend(units_q.iter().filter_map(|&b| targets[cell(a, b)]));
+            }
+            let class = class_targets.len();
+            class_weights.push(samples.len());
+            class_targets.push(median(&mut samples));
+
+            for &flag_a in flags_p.iter() {
+                for &flag_b in flags_q.iter() {
+                    candidates[flag_a * OBJMASK_INFO.len() + flag_b].push(class);
+                }
+            }
+        }
+    }
+
+    let mut class_predicted = vec![0.0f32; class_targets.len()];
+    let mut flag_factors = vec![0.0f32; candidates.len()];
+    let mut residuals = Vec::new();
+    loop {
+        let mut best: Option<(usize, f32, usize)> = None;
+        for (candidate, classes) in candidates.iter().enumerate() {
+            residuals.clear();
+            residuals.extend(classes.iter().filter_map(|&class| {
+                class_targets[class].map(|target| (target - class_predicted[class], class_weights[class]))
+            }));
+
+            if let Some((shift, gain)) = best_shift(&mut residuals) {
+                if best.is_none_or(|(_, _, best_gain)| gain > best_gain) {
+                    best = Some((candidate, shift, gain));
+                }
+            }
+        }
+
+        let (candidate, shift) = match best {
+            Some((candidate, shift, gain)) if gain >= MIN_GAIN => (candidate, shift),
+            _ => break,
+        };
+
+        flag_factors[candidate] += shift;
+        for &class in &candidates[candidate] {
+            class_predicted[class] += shift;
+        }
+    }
+
+    let mut source = UnitBalance::default();
+    let mut objmask_factor_count = 0;
+    for (candidate, &factor) in flag_factors.iter().enumerate() {
+        if let Some(modifier) = log_to_modifier(factor) {
+            let flag_a = OBJMASK_INFO[candidate / OBJMASK_INFO.len()].1;
+            let flag_b = OBJMASK_INFO[candidate % OBJMASK_INFO.len()].1;
+            source.entries.entry(flag_a.to_owned()).or_default()
+                .modifiers.insert(flag_b.to_owned(), modifier);
+            objmask_factor_count += 1;
+        }
+    }
+
+    let mut predicted = vec![0.0f32; n * n];
+    let mut class = 0;
+    for (_, units_p) in &signatures {
+        for (_, units_q) in &signatures {
+            for &a in units_p {
+                for &b in units_q {
+                    predicted[cell(a, b)] = class_predicted[class];
+                }
+            }
+            class += 1;
+        }
+    }
+
+    let mut units_with_flag = vec![Vec::new(); OBJMASK_INFO.len()];
+    for (i, (_, flags)) in units.iter().enumerate() {
+        for &flag in flags {
+            units_with_flag[flag].push(i);
+        }
+    }
+
+    // Unit row × objmask column and objmask row × unit column factors.
+    let mut unit_factor_count = 0;
+    let mut cells = Vec::new();
+    for (a, &(unit_a, _)) in units.iter().enumerate() {
+        for (flag_b, with_flag_b) in units_with_flag.iter().enumerate() {
+            cells.clear();
+            cells.extend(with_flag_b.iter().map(|&b| cell(a, b)));
+            if let Some(modifier) = fit_unit_factor(&cells, &targets, &mut predicted, &mut residuals) {
+                source.entries.entry(unit_a.to_owned()).or_default()
+                    .modifiers.insert(OBJMASK_INFO[flag_b].1.to_owned(), modifier);
+                unit_factor_count += 1;
+            }
+        }
+    }
+    for (b, &(unit_b, _)) in units.iter().enumerate() {
+        for (flag_a, with_flag_a) in units_with_flag.iter().enumerate() {
+            cells.clear();
+            cells.extend(with_flag_a.iter().map(|&a| cell(a, b)));
+            if let Some(modifier) = fit_unit_factor(&cells, &targets, &mut predicted, &mut residuals) {
+                source.entries.entry(OBJMASK_INFO[flag_a].1.to_owned()).or_default()
+                    .modifiers.insert(unit_b.to_owned(), modifier);
+                unit_factor_count += 1;
+            }
+        }
+    }
+
+    // Rebuild the table from the factors and override whatever still
+    // differs.
+    let rebuilt = calculate_new_balance(&unit_objmask_map, &source, &BalanceOptions::default());
+    let mut override_count = 0;
+    let mut approximate_count = 0;
+    for (a, &(unit_a, _)) in units.iter().enumerate() {
+        for (b, &(unit_b, _)) in units.iter().enumerate() {
+            let value = match values[cell(a, b)] {
+                Some(value) => value,
+                None => continue,
+            };
+            let rebuilt_value = rebuilt.entries[unit_a].modifiers[unit_b];
+            if reproduces(rebuilt_value, value) {
+                continue;
+            }
+
+            let modifier = match residual_override(rebuilt_value, value) {
+                Some(modifier) if modifier != 100.0 => modifier,
+                _ => {
+                    eprintln!("Warning: {} vs {} can only be reproduced approximately ({} instead of {})",
+                              unit_a, unit_b, rebuilt_value, value);
+                    approximate_count += 1;
+                    continue;
+                }
+            };
+            source.entries.entry(unit_a.to_owned()).or_default()
+                .modifiers.insert(unit_b.to_owned(), modifier);
+            override_count += 1;
+        }
+    }
+
+    eprintln!("Found {} objmask factors, {} unit factors and {} unit overrides for {} units",
+              objmask_factor_count, unit_factor_count, override_count, n);
+    if approximate_count > 0 {
+        eprintln!("Warning: {} cells could only be reproduced approximately", approximate_count);
+    }
+
+    write_exact_balance(&mut std::io::stdout(), &source)
+        .map_err(|e| format!("Failed to write factorised balance.xml file: {}", e))
+}
+
+/// Find the shift that brings the most weight of `residuals` within
+/// `RESIDUAL_TOLERANCE` of zero, returning it along with how much more
+/// weight it explains than leaving the residuals unchanged.
+fn best_shift(residuals: &mut [(f32, usize)]) -> Option<(f32, usize)> {
+    residuals.sort_unstable_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
+
+    let explained: usize = residuals.iter()
+        .filter(|(r, _)| r.abs() <= RESIDUAL_TOLERANCE)
+        .map(|&(_, w)| w)
+        .sum();
+
+    // Slide a window of width 2 * RESIDUAL_TOLERANCE over the sorted
+    // residuals, centring the shift on the heaviest one.
+    let mut best: Option<(f32, usize)> = None;
+    let mut window_weight = 0;
+    let mut start = 0;
+    for end in 0..residuals.len() {
+        window_weight += residuals[end].1;
+        while residuals[end].0 - residuals[start].0 > 2.0 * RESIDUAL_TOLERANCE {
+            window_weight -= residuals[start].1;
+            start += 1;
+        }
+        if best.is_none_or(|(_, best_weight)| window_weight > best_weight) {
+            best = Some(((residuals[start].0 + residuals[end].0) / 2.0, window_weight));
+        }
+    }
+
+    best.and_then(|(shift, weight)| weight.checked_sub(explained).map(|gain| (shift, gain)))
+}
+
+/// Fit a single factor shared by `cells`, returning the modifier if it
+/// explains enough additional cells.
+fn fit_unit_factor(cells: &[usize], targets: &[Option<f32>], predicted: &mut [f32],
+                   residuals: &mut Vec<(f32, usize)>) -> Option<f32> {
+    residuals.clear();
+    residuals.extend(cells.iter().filter_map(|&i| targets[i].map(|target| (target - predicted[i], 1))));
+
+    let shift = match best_shift(residuals) {
+        Some((shift, gain)) if gain >= MIN_GAIN => shift,
+        _ => return None,
+    };
+
+    let modifier = log_to_modifier(shift)?;
+    for &i in cells {
+        predicted[i] += shift;
+    }
+
+    Some(modifier)
+}
+
+/// Whether a rebuilt cell matches the flattened `value`. Flattened cells
+/// are written as whole percentages, so those only need to round to the
+/// same value, but fractional values must match to two decimal places.
+fn reproduces(rebuilt: f32, value: f32) -> bool {
+    if value.fract() == 0.0 {
+        rebuilt.round() == value
+    } else {
+        (rebuilt - value).abs() < 0.005
+    }
+}
+
+/// Find the unit override that turns `rebuilt` into `value`, with as few
+/// decimal places as possible, or `None` if no override can.
+fn residual_override(rebuilt: f32, value: f32) -> Option<f32> {
+    if value == 0.0 {
+        return Some(0.0);
+    }
+    if rebuilt == 0.0 {
+        return None;
+    }
+
+    let ideal = 100.0 * value / rebuilt;
+    (0..4)
+        .map(|decimals| round_to(ideal, decimals))
+        .find(|&modifier| reproduces(rebuilt * modifier / 100.0, value))
+        .or(Some(ideal))
+}
+
+/// Convert a factor in log space to a modifier rounded to two decimal
+/// places, or `None` if it rounds to the neutral 100.
+fn log_to_modifier(factor: f32) -> Option<f32> {
+    let modifier = round_to(factor.exp() * 100.0, 2);
+    if modifier == 100.0 {
+        None
+    } else {
+        Some(modifier)
+    }
+}
+
+fn round_to(value: f32, decimals: i32) -> f32 {
+    let scale = 10f32.powi(decimals);
+    (value * scale).round() / scale
+}
+
+fn median(values: &mut [f32]) -> Option<f32> {
+    if values.is_empty() {
+        return None;
+    }
+
+    let mid = values.len() / 2;
+    let (_, &mut median, _) = values.select_nth_unstable_by(mid, |a, b| a.partial_cmp(b).unwrap());
+    Some(median)
+}
@@ -0,0 +1,122 @@
+//! Rewriting of balance files in a canonical layout, without flattening
+//! them.
+
+use std::io::Write;
+use std::path::Path;
+
+use crate::{parse_balance_document, write_new_document, MergePolicy, OutputFormat, UnitBalance};
+
+/// Order of the entries and attributes in a formatted balance file.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+enum SortOrder {
+    /// Keep the order of the input file.
+    #[default]
+    Keep,
+    /// Sort entries and attributes by name, keeping the `name` attribute
+    /// first and attributes that are not numbers last.
+    Name,
+}
+
+impl SortOrder {
+    fn from_name(name: &str) -> Option<SortOrder> {
+        match name {
+            "keep" => Some(SortOrder::Keep),
+            "name" => Some(SortOrder::Name),
+            _ => None,
+        }
+    }
+
+    fn sort(self, unit_balance: &mut UnitBalance) {
+        if self == SortOrder::Keep {
+            return;
+        }
+
+        unit_balance.entries.sort_keys();
+        for entry in unit_balance.entries.values_mut() {
+            entry.modifiers.sort_keys();
+            entry.passthrough.sort_keys();
+        }
+    }
+}
+
+pub fn run_fmt(args: &[String]) -> Result<(), String> {
+    let mut check = false;
+    let mut keep_values = false;
+    let mut sort_order = SortOrder::default();
+    let mut format = OutputFormat::default();
+    let mut balance_xml_paths = Vec::new();
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or_else(|| format!("Missing value for option \"{}\"", arg));
+        match arg.as_str() {
+            "--check" => check = true,
+            "--keep-values" => keep_values = true,
+            "--sort" => {
+                let name = value()?;
+                sort_order = SortOrder::from_name(name)
+                    .ok_or_else(|| format!("Unknown sort order \"{}\"", name))?;
+            }
+            _ if format.parse_option(arg, &mut value)? => (),
+            _ if arg.starts_with('-') && arg != "-" => return Err(format!("Unknown option \"{}\"", arg)),
+            _ => balance_xml_paths.push(Path::new(arg)),
+        }
+    }
+
+    format.validate()?;
+    // Values are never rounded, only written in their shortest form.
+    format.exact_values = true;
+
+    if balance_xml_paths.is_empty() || (!check && balance_xml_paths.len() > 1) {
+        return Err("Usage: fmt [--check] [--sort <order>] [--keep-values] [options] <balance file>, or several balance \
+                    files with --check".to_owned());
+    }
+
+    if check && balance_xml_paths.contains(&Path::new("-")) {
+        return Err("--check cannot read balance.xml from standard input".to_owned());
+    }
+
+    if !check {
+        let formatted = format_balance(balance_xml_paths[0], sort_order, keep_values, &format)?;
+        return std::io::stdout().write_all(&formatted)
+            .map_err(|e| format!("Failed to write new balance.xml file: {}", e));
+    }
+
+    let mut unformatted = Vec::new();
+    for balance_xml_path in balance_xml_paths {
+        let formatted = format_balance(balance_xml_path, sort_order, keep_values, &format)?;
+        let original = std::fs::read(balance_xml_path)
+            .map_err(|e| format!("Failed to read balance.xml: {}", e))?;
+        if original != formatted {
+            eprintln!("{} is not formatted", balance_xml_path.display());
+            unformatted.push(balance_xml_path);
+        }
+    }
+
+    if !unformatted.is_empty() {
+        let unformatted: Vec<_> = unformatted.iter().map(|path| path.display().to_string()).collect();
+        return Err(format!("Not formatted: {}", unformatted.join(", ")));
+    }
+
+    Ok(())
+}
+
+/// Parse a balance file and write it back out, in the encoding it was read
+/// in. Unless `keep_values` is set, values are written in their shortest
+/// form rather than as they were, so "100.0" becomes "100".
+fn format_balance(balance_xml_path: &Path, sort_order: SortOrder, keep_values: bool,
+                  format: &OutputFormat) -> Result<Vec<u8>, String> {
+    let (mut document, encoding) = parse_balance_document(balance_xml_path, MergePolicy::default())?;
+    for table in &mut document.tables {
+        sort_order.sort(&mut table.unit_balance);
+        if !keep_values {
+            for entry in table.unit_balance.entries.values_mut() {
+                entry.lexical.clear();
+            }
+        }
+    }
+
+    let mut formatted = Vec::new();
+    write_new_document(&mut formatted, &document, encoding, format)?;
+    Ok(formatted)
+}
@@ -0,0 +1,221 @@
+//! Export of the flattened balance as a Graphviz graph of which units
+//! counter which.
+
+use std::fmt::Write as _;
+use std::io::Write;
+use std::path::Path;
+
+use crate::report::{format_modifier, ReportArgs};
+use crate::{units, FnvIndexMap, UnitBalance, UnitObjmaskMap, OBJMASK_INFO};
+
+/// Directed graph with an edge from A to B wherever A's flattened modifier
+/// against B is above the threshold.
+struct CounterGraph<'a> {
+    units: Vec<&'a str>,
+    /// Target unit index and modifier of the edges from each unit.
+    edges: Vec<Vec<(usize, f32)>>,
+}
+
+impl<'a> CounterGraph<'a> {
+    fn new(unit_objmask_map: &'a UnitObjmaskMap, new_unit_balance: &UnitBalance, threshold: f32) -> CounterGraph<'a> {
+        let units: Vec<&str> = units(unit_objmask_map).map(|unit| unit.as_str()).collect();
+        let edges = units.iter()
+            .enumerate()
+            .map(|(a, unit_a)| {
+                let entry = match new_unit_balance.entries.get(*unit_a) {
+                    Some(entry) => entry,
+                    None => return Vec::new(),
+                };
+
+                units.iter()
+                    .enumerate()
+                    .filter(|&(b, _)| b != a)
+                    .filter_map(|(b, unit_b)| entry.modifiers.get(*unit_b).map(|&modifier| (b, modifier)))
+                    .filter(|&(_, modifier)| modifier > threshold)
+                    .collect()
+            })
+            .collect();
+
+        CounterGraph { units, edges }
+    }
+
+    /// Find the strongly connected components with more than one unit,
+    /// each of which contains at least one counter loop. Returns the
+    /// component index of every unit, if it is in such a component.
+    fn counter_loops(&self) -> Vec<Option<usize>> {
+        // Tarjan's algorithm, with an explicit stack of (unit, next edge).
+        let unit_count = self.units.len();
+        let mut index = vec![None; unit_count];
+        let mut low_link = vec![0; unit_count];
+        let mut on_stack = vec![false; unit_count];
+        let mut stack = Vec::new();
+        let mut component = vec![None; unit_count];
+        let mut next_index = 0;
+        let mut next_component = 0;
+
+        for root in 0..unit_count {
+            if index[root].is_some() {
+                continue;
+            }
+
+            let mut call_stack = vec![(root, 0)];
+            index[root] = Some(next_index);
+            low_link[root] = next_index;
+            next_index += 1;
+            stack.push(root);
+            on_stack[root] = true;
+
+            while let Some(&mut (unit, ref mut next_edge)) = call_stack.last_mut() {
+                if let Some(&(target, _)) = self.edges[unit].get(*next_edge) {
+                    *next_edge += 1;
+                    match index[target] {
+                        None => {
+                            index[target] = Some(next_index);
+                            low_link[target] = next_index;
+                            next_index += 1;
+                            stack.push(target);
+                            on_stack[target] = true;
+                            call_stack.push((target, 0));
+                        }
+                        Some(target_index) if on_stack[target] => {
+                            low_link[unit] = low_link[unit].min(target_index);
+                        }
+                        Some(_) => (),
+                    }
+                    continue;
+                }
+
+                call_stack.pop();
+                if let Some(&(parent, _)) = call_stack.last() {
+                    low_link[parent] = low_link[parent].min(low_link[unit]);
+                }
+
+                if Some(low_link[unit]) == index[unit] {
+                    let mut members = Vec::new();
+                    loop {
+                        let member = stack.pop().unwrap();
+                        on_stack[member] = false;
+                        members.push(member);
+                        if member == unit {
+                            break;
+                        }
+                    }
+
+                    if members.len() > 1 {
+                        for member in members {
+                            component[member] = Some(next_component);
+                        }
+                        next_component += 1;
+                    }
+                }
+            }
+        }
+
+        component
+    }
+}
+
+pub fn run_graph(args: &[String]) -> Result<(), String> {
+    let usage = "Usage: graph [--unitrules <file>] [--combine <mode>] [--threshold <value>] [--cycles] \
+                 <balance file>";
+
+    let mut threshold = 150.0;
+    let mut cycles = false;
+    let args = ReportArgs::parse(args, &["--combine"], |arg, value| {
+        match arg {
+            "--threshold" => {
+                let value = value()?;
+                threshold = value.parse()
+                    .map_err(|e| format!("Failed to parse threshold \"{}\": {}", value, e))?;
+            }
+            "--cycles" => cycles = true,
+            _ => return Ok(false),
+        }
+
+        Ok(true)
+    })?;
+
+    let balance_xml_path = match args.positional.as_slice() {
+        [balance] => Path::new(balance),
+        _ => return Err(usage.to_owned()),
+    };
+
+    let (unit_objmask_map, _, new_unit_balance) = args.read_flattened(balance_xml_path)?;
+    let graph = CounterGraph::new(&unit_objmask_map, &new_unit_balance, threshold);
+
+    let counter_loops = if cycles {
+        let counter_loops = graph.counter_loops();
+        let mut loop_members: FnvIndexMap<usize, Vec<&str>> = FnvIndexMap::default();
+        for (unit, component) in graph.units.iter().zip(&counter_loops) {
+            if let Some(component) = component {
+                loop_members.entry(*component).or_default().push(unit);
+            }
+        }
+        for members in loop_members.values() {
+            eprintln!("Counter loop: {}", members.join(", "));
+        }
+        Some(counter_loops)
+    } else {
+        None
+    };
+
+    let dot = write_dot(&graph, &unit_objmask_map, counter_loops.as_deref());
+    std::io::stdout().write_all(dot.as_bytes())
+        .map_err(|e| format!("Failed to write graph: {}", e))
+}
+
+/// Render the graph as DOT, with each unit clustered under the first of
+/// its objmask flags and counter loop edges highlighted.
+fn write_dot(graph: &CounterGraph, unit_objmask_map: &UnitObjmaskMap,
+             counter_loops: Option<&[Option<usize>]>) -> String {
+    let mut clusters: FnvIndexMap<&str, Vec<&str>> = FnvIndexMap::default();
+    let mut unclustered = Vec::new();
+    for unit in &graph.units {
+        let objmask = &unit_objmask_map[*unit];
+        match OBJMASK_INFO.iter().find(|(_, attrib)| objmask.contains(attrib)) {
+            Some((_, flag)) => clusters.entry(flag).or_default().push(unit),
+            None => unclustered.push(*unit),
+        }
+    }
+    clusters.sort_by(|a, _, b, _| {
+        let position = |flag| OBJMASK_INFO.iter().position(|(_, attrib)| *attrib == flag);
+        position(*a).cmp(&position(*b))
+    });
+
+    let mut dot = String::new();
+    dot.push_str("digraph counters {\n");
+    for (flag, units) in &clusters {
+        writeln!(dot, "    subgraph {} {{", quote(&format!("cluster_{}", flag))).unwrap();
+        writeln!(dot, "        label = {};", quote(flag)).unwrap();
+        for unit in units {
+            writeln!(dot, "        {};", quote(unit)).unwrap();
+        }
+        dot.push_str("    }\n");
+    }
+    for unit in unclustered {
+        writeln!(dot, "    {};", quote(unit)).unwrap();
+    }
+
+    let in_same_loop = |a: usize, b: usize| match counter_loops {
+        Some(counter_loops) => counter_loops[a].is_some() && counter_loops[a] == counter_loops[b],
+        None => false,
+    };
+
+    for (a, edges) in graph.edges.iter().enumerate() {
+        for &(b, modifier) in edges {
+            write!(dot, "    {} -> {} [weight = {}, label = \"{}\"", quote(graph.units[a]), quote(graph.units[b]),
+                   modifier.round() as i64, format_modifier(modifier)).unwrap();
+            if in_same_loop(a, b) {
+                dot.push_str(", color = red");
+            }
+            dot.push_str("];\n");
+        }
+    }
+    dot.push_str("}\n");
+
+    dot
+}
+
+fn quote(id: &str) -> String {
+    format!("\"{}\"", id.replace('\\', "\\\\").replace('"', "\\\""))
+}
@@ -0,0 +1,165 @@
+//! Per-flag report of the unit pairs that the game's objmask bug affects.
+
+use std::io::Write;
+use std::path::Path;
+
+use crate::combine::{Combiner, Factor, Multiplicative};
+use crate::report::{format_modifier, ReportArgs, Table};
+use crate::{collect_factors, FnvIndexMap, UnitBalance, UnitObjmaskMap, OBJMASK_INFO};
+
+/// The error in one unit pair from the game ignoring a flag's cells.
+#[derive(Clone, Debug)]
+struct PairError {
+    unit_a: String,
+    unit_b: String,
+    /// The modifier without the flag's cells minus the intended modifier.
+    error: f32,
+    /// `error` as a percentage of the intended modifier.
+    relative_error: f32,
+}
+
+#[derive(Clone, Debug, Default)]
+struct FlagImpact {
+    pairs: usize,
+    largest: Option<PairError>,
+    largest_relative: Option<PairError>,
+    /// Number of affected pairs and their total absolute error, by unit.
+    attackers: FnvIndexMap<String, (usize, f32)>,
+    targets: FnvIndexMap<String, (usize, f32)>,
+}
+
+impl FlagImpact {
+    fn add(&mut self, pair_error: PairError) {
+        self.pairs += 1;
+
+        for (units, unit) in [(&mut self.attackers, &pair_error.unit_a), (&mut self.targets, &pair_error.unit_b)] {
+            let (pairs, total_error) = units.entry(unit.clone()).or_default();
+            *pairs += 1;
+            *total_error += pair_error.error.abs();
+        }
+
+        if self.largest.as_ref().is_none_or(|largest| pair_error.error.abs() > largest.error.abs()) {
+            self.largest = Some(pair_error.clone());
+        }
+        if self.largest_relative.as_ref()
+            .is_none_or(|largest| pair_error.relative_error.abs() > largest.relative_error.abs()) {
+            self.largest_relative = Some(pair_error);
+        }
+    }
+}
+
+/// Work out, for every objmask flag, which unit pairs lose a modifier
+/// because the game ignores the flag's rows and columns.
+fn calculate_impact(unit_objmask_map: &UnitObjmaskMap,
+                    unit_balance: &UnitBalance) -> FnvIndexMap<&'static str, FlagImpact> {
+    let mut impact: FnvIndexMap<&'static str, FlagImpact> = FnvIndexMap::default();
+    let mut factors = Vec::new();
+    let mut remaining_factors: Vec<Factor> = Vec::new();
+
+    for (unit_a, unit_a_objmask) in unit_objmask_map.iter() {
+        for (unit_b, unit_b_objmask) in unit_objmask_map.iter() {
+            collect_factors(unit_a, unit_a_objmask, unit_b, unit_b_objmask, unit_balance, &mut factors);
+            let intended = Multiplicative.combine(&factors);
+
+            for &(_, flag) in OBJMASK_INFO.iter() {
+                let involves_flag = |factor: &Factor| factor.attacker == flag || factor.target == flag;
+                if !factors.iter().any(|factor| involves_flag(factor) && factor.modifier != 100.0) {
+                    continue;
+                }
+
+                remaining_factors.clear();
+                remaining_factors.extend(factors.iter().filter(|factor| !involves_flag(factor)));
+                let error = Multiplicative.combine(&remaining_factors) - intended;
+                if error.abs() < 0.005 {
+                    // The flag's cells cancel each other out.
+                    continue;
+                }
+
+                let relative_error = if intended == 0.0 {
+                    f32::INFINITY
+                } else {
+                    error / intended * 100.0
+                };
+
+                impact.entry(flag).or_default().add(PairError {
+                    unit_a: unit_a.clone(),
+                    unit_b: unit_b.clone(),
+                    error,
+                    relative_error,
+                });
+            }
+        }
+    }
+
+    // Most widespread first.
+    impact.sort_by(|_, a, _, b| b.pairs.cmp(&a.pairs));
+    impact
+}
+
+pub fn run_impact(args: &[String]) -> Result<(), String> {
+    let usage = "Usage: impact [--unitrules <file>] [--top <count>] <balance file>";
+
+    let args = ReportArgs::parse(args, &["--top"], |_, _| Ok(false))?;
+    let balance_xml_path = match args.positional.as_slice() {
+        [balance] => Path::new(balance),
+        _ => return Err(usage.to_owned()),
+    };
+
+    let (unit_objmask_map, unit_balance) = args.read_inputs(balance_xml_path)?;
+    let impact = calculate_impact(&unit_objmask_map, &unit_balance);
+
+    write_impact(&mut std::io::stdout(), &impact, args.top)
+        .map_err(|e| format!("Failed to write impact report: {}", e))
+}
+
+fn write_impact(writer: &mut dyn Write, impact: &FnvIndexMap<&'static str, FlagImpact>,
+                top: usize) -> std::io::Result<()> {
+    if impact.is_empty() {
+        return writeln!(writer, "No unit pairs are affected");
+    }
+
+    let describe = |pair_error: &Option<PairError>, error: fn(&PairError) -> String| match pair_error {
+        Some(pair_error) => format!("{} vs {} ({})", pair_error.unit_a, pair_error.unit_b, error(pair_error)),
+        None => String::new(),
+    };
+
+    let mut summary = Table::new(&["Flag", "Pairs", "Largest error", "Largest relative error"]);
+    for (flag, flag_impact) in impact {
+        summary.add_row(vec![
+            flag.to_string(),
+            flag_impact.pairs.to_string(),
+            describe(&flag_impact.largest, |pair_error| format_signed(pair_error.error)),
+            describe(&flag_impact.largest_relative, |pair_error| {
+                format!("{}%", format_signed(pair_error.relative_error))
+            }),
+        ]);
+    }
+    summary.write(writer)?;
+
+    for (flag, flag_impact) in impact {
+        writeln!(writer)?;
+        writeln!(writer, "{}", flag)?;
+
+        for (heading, units) in [("Attacker", &flag_impact.attackers), ("Target", &flag_impact.targets)] {
+            let mut units: Vec<_> = units.iter().collect();
+            units.sort_by(|(_, (_, a)), (_, (_, b))| b.total_cmp(a));
+
+            let mut table = Table::new(&[heading, "Pairs", "Total error"]);
+            for (unit, (pairs, total_error)) in units.into_iter().take(top) {
+                table.add_row(vec![unit.clone(), pairs.to_string(), format_modifier(*total_error)]);
+            }
+            writeln!(writer)?;
+            table.write(writer)?;
+        }
+    }
+
+    Ok(())
+}
+
+fn format_signed(value: f32) -> String {
+    if value > 0.0 {
+        format!("+{}", format_modifier(value))
+    } else {
+        format_modifier(value)
+    }
+}
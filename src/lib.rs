@@ -0,0 +1,7 @@
+//! Library interface of ron-objmask-workaround.
+//!
+//! Only the strategies for combining the balance modifiers that apply to a
+//! unit pair are exported, so that other tools can flatten cells with the
+//! same semantics as `--combine`, or with a `Combiner` of their own.
+
+pub mod combine;
@@ -1,20 +1,46 @@
+use std::borrow::Cow;
 use std::fs::File;
-use std::io::{BufReader, BufWriter, Write};
-use std::path::Path;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
 
-use fnv::{FnvBuildHasher, FnvHashSet};
+use fnv::{FnvBuildHasher, FnvHashMap, FnvHashSet};
 
 use indexmap::IndexMap;
 
-use quick_xml::events::{BytesDecl, BytesEnd, BytesStart, Event};
+use quick_xml::events::{BytesDecl, BytesEnd, BytesStart, BytesText, Event};
 use quick_xml::{Reader, Writer};
 
+use ron_objmask_workaround::combine::{self, Combiner, Factor};
+
+use encoding_rs::{Encoding, UTF_8};
+
 #[cfg(windows)]
 use wchar::wch_c;
 
 #[cfg(windows)]
 use winapi::Interface;
 
+mod anomalies;
+mod classes;
+#[cfg(feature = "serde")]
+mod convert;
+mod encoding;
+mod counters;
+mod engine;
+mod factorize;
+mod fmt;
+mod graph;
+mod impact;
+mod overrides;
+mod patch;
+mod provenance;
+mod report;
+mod selector;
+mod subset;
+mod update;
+#[cfg(feature = "serde")]
+mod source;
+
 #[cfg(windows)]
 mod w32 {
     pub use winapi::shared::winerror::*;
@@ -29,6 +55,8 @@ mod w32 {
 
 type FnvIndexMap<K, V> = IndexMap<K, V, FnvBuildHasher>;
 
+type UnitObjmaskMap = FnvIndexMap<String, FnvHashSet<&'static str>>;
+
 #[cfg(windows)]
 unsafe fn from_utf16_nul(s: *const u16) -> String {
     let mut len = 0;
@@ -229,32 +257,298 @@ fn char_to_attrib_str(c: char) -> Option<&'static str> {
     OBJMASK_INFO.iter().find(|(c2, _)| c2 == &c).map(|(_, attrib)| *attrib)
 }
 
+/// Format an objmask as its flag character codes, in `OBJMASK_INFO`
+/// order.
+fn objmask_to_string(objmask: &FnvHashSet<&'static str>) -> String {
+    OBJMASK_INFO.iter()
+        .filter(|(_, attrib)| objmask.contains(attrib))
+        .map(|&(c, _)| c)
+        .collect()
+}
+
+/// Look up an objmask flag by either its full attribute name or its
+/// single character code.
+fn objmask_name_to_attrib_str(name: &str) -> Option<&'static str> {
+    let mut chars = name.chars();
+    match (chars.next(), chars.next()) {
+        (Some(c), None) => char_to_attrib_str(c),
+        _ => OBJMASK_INFO.iter().find(|(_, attrib)| *attrib == name).map(|(_, attrib)| *attrib),
+    }
+}
+
+/// Options controlling how `calculate_new_balance` flattens the balance
+/// table.
+#[derive(Clone, Copy, Debug)]
+struct BalanceOptions {
+    /// How the modifiers applying to a unit pair are combined.
+    combiner: &'static dyn Combiner,
+    /// Bit set of the `OBJMASK_INFO` flags to expand. Flags that are not
+    /// expanded are left in the table as they were.
+    expanded_flags: u32,
+}
+
+impl Default for BalanceOptions {
+    fn default() -> BalanceOptions {
+        BalanceOptions {
+            combiner: &combine::Multiplicative,
+            expanded_flags: !0,
+        }
+    }
+}
+
+impl BalanceOptions {
+    fn expands_flag(&self, objmask_name: &str) -> bool {
+        OBJMASK_INFO.iter()
+            .position(|&(_, attrib)| attrib == objmask_name)
+            .is_some_and(|i| self.expanded_flags & (1 << i) != 0)
+    }
+}
+
+/// Layout of a written balance file.
+#[derive(Clone, Copy, Debug)]
+struct OutputFormat {
+    /// Character each level is indented with, a space or a tab.
+    indent_char: u8,
+    /// Number of `indent_char` per level.
+    indent_width: usize,
+    /// Write each attribute of an ENTRY on its own line.
+    attribute_per_line: bool,
+    /// End lines with CRLF rather than LF.
+    crlf: bool,
+    /// Write the whole file on one line without indentation.
+    compact: bool,
+    /// Write modifiers as they are rather than rounded to whole
+    /// percentages, for tables that are not a finished balance.xml.
+    exact_values: bool,
+}
+
+impl Default for OutputFormat {
+    fn default() -> OutputFormat {
+        OutputFormat {
+            indent_char: b' ',
+            indent_width: 2,
+            attribute_per_line: false,
+            crlf: false,
+            compact: false,
+            exact_values: false,
+        }
+    }
+}
+
+impl OutputFormat {
+    /// Apply `arg` if it is one of the output layout options, taking its
+    /// value from `value`. Returns whether it was.
+    fn parse_option<'a>(&mut self, arg: &str,
+                        value: &mut dyn FnMut() -> Result<&'a String, String>) -> Result<bool, String> {
+        match arg {
+            "--indent" => {
+                self.indent_char = match value()?.as_str() {
+                    "space" => b' ',
+                    "tab" => b'\t',
+                    indent => return Err(format!("Unknown indent \"{}\", expected \"space\" or \"tab\"", indent)),
+                };
+            }
+            "--indent-width" => {
+                let width = value()?;
+                self.indent_width = width.parse()
+                    .map_err(|e| format!("Failed to parse indent width \"{}\": {}", width, e))?;
+            }
+            "--attribute-per-line" => self.attribute_per_line = true,
+            "--crlf" => self.crlf = true,
+            "--compact" => self.compact = true,
+            _ => return Ok(false),
+        }
+
+        Ok(true)
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if self.compact && self.attribute_per_line {
+            return Err("--compact cannot be combined with --attribute-per-line".to_owned());
+        }
+
+        Ok(())
+    }
+
+    fn line_ending(&self) -> &'static [u8] {
+        if self.crlf { b"\r\n" } else { b"\n" }
+    }
+
+    /// Content kept from the input with its line endings changed to this
+    /// layout's. When compact, its lines are joined without their
+    /// indentation, with a space between lines of text.
+    fn kept_content(&self, content: &str) -> Vec<u8> {
+        let mut lines = content.split('\n').map(|line| line.strip_suffix('\r').unwrap_or(line));
+        let mut kept_content = lines.next().unwrap_or_default().as_bytes().to_vec();
+        for line in lines {
+            if self.compact {
+                let line = line.trim_start();
+                if line.is_empty() {
+                    continue;
+                }
+                // Lines of text are separated by a space, markup is joined.
+                let markup = kept_content.ends_with(b">") && line.starts_with('<');
+                if !kept_content.is_empty() && !markup {
+                    kept_content.push(b' ');
+                }
+                kept_content.extend_from_slice(line.as_bytes());
+            } else {
+                kept_content.extend_from_slice(self.line_ending());
+                kept_content.extend_from_slice(line.as_bytes());
+            }
+        }
+
+        kept_content
+    }
+
+    /// The line ending and indentation before an element at `level`.
+    fn line_break(&self, level: usize) -> Vec<u8> {
+        if self.compact {
+            return Vec::new();
+        }
+
+        let mut line_break = self.line_ending().to_vec();
+        line_break.resize(line_break.len() + level * self.indent_width, self.indent_char);
+        line_break
+    }
+}
+
 #[derive(Clone, Debug, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize), serde(transparent))]
 struct UnitBalance {
     entries: FnvIndexMap<String, UnitBalanceEntry>,
 }
 
 #[derive(Clone, Debug, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize), serde(transparent))]
 struct UnitBalanceEntry {
     modifiers: FnvIndexMap<String, f32>,
+    /// Attributes whose values are not numbers, written back out as they
+    /// were.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    passthrough: FnvIndexMap<String, String>,
+    /// Raw XML content of the ENTRY element, written back out as it was.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    children: String,
+    /// Modifiers as they were read and written in balance.xml, so that
+    /// cells whose value is unchanged keep their original form.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    lexical: FnvIndexMap<String, (f32, String)>,
+}
+
+/// A balance file, which may hold several tables.
+///
+/// Comments and elements that are not balance tables are kept as they were
+/// written, so that they can be written back out in place.
+#[derive(Clone, Debug, Default)]
+struct BalanceDocument {
+    /// Lines of the provenance header comment, see `provenance`.
+    header: Vec<String>,
+    /// Whether the file started with a UTF-8 byte order mark.
+    utf8_bom: bool,
+    /// Content before the root element, such as comments.
+    prolog: Vec<String>,
+    /// Attributes of the root element.
+    root_attributes: Vec<(String, String)>,
+    /// Content of the root element other than tables, each with the index
+    /// of the table it comes before.
+    kept: Vec<(usize, String)>,
+    tables: Vec<BalanceTable>,
+}
+
+#[derive(Clone, Debug, Default)]
+struct BalanceTable {
+    /// Attributes of the TABLE element, such as its name.
+    attributes: Vec<(String, String)>,
+    /// Whether this holds ENTRY elements given directly inside the root
+    /// element, which are written back out without a TABLE element.
+    implicit: bool,
+    /// Content of the table other than entries, each with the name of the
+    /// entry it comes before, or none if it comes after them all.
+    kept: Vec<(Option<String>, String)>,
+    unit_balance: UnitBalance,
+}
+
+impl BalanceTable {
+    fn name(&self) -> Option<&str> {
+        self.attributes.iter().find(|(key, _)| key == "name").map(|(_, value)| value.as_str())
+    }
+}
+
+impl BalanceDocument {
+    /// Find a table by its name or its position, counting from 1.
+    fn find_table(&self, selector: &str) -> Option<usize> {
+        self.tables.iter().position(|table| table.name() == Some(selector))
+            .or_else(|| match selector.parse::<usize>() {
+                Ok(number) if number >= 1 && number <= self.tables.len() => Some(number - 1),
+                _ => None,
+            })
+    }
+
+    /// Find the tables named or numbered by `selectors`, or the first
+    /// table if there are none.
+    fn select_tables(&self, selectors: &[String]) -> Result<Vec<usize>, String> {
+        if selectors.is_empty() {
+            return Ok((0..self.tables.len().min(1)).collect());
+        }
+
+        selectors.iter()
+            .map(|selector| {
+                self.find_table(selector)
+                    .ok_or_else(|| format!("No table named or numbered \"{}\" in balance.xml", selector))
+            })
+            .collect()
+    }
+
+    /// Find the table matching the table of `other` at `index`: the table
+    /// with the same name, or in the same position if it has none.
+    fn matching_table(&self, other: &BalanceDocument, index: usize) -> Option<usize> {
+        match other.tables[index].name() {
+            Some(name) => self.find_table(name),
+            None => Some(index).filter(|&index| index < self.tables.len()),
+        }
+    }
+
+    /// Describe the table at `index` for messages.
+    fn describe_table(&self, index: usize) -> String {
+        match self.tables[index].name() {
+            Some(name) => format!("table \"{}\"", name),
+            None => format!("table {}", index + 1),
+        }
+    }
 }
 
 fn main() {
     // Handle COM init/deinit.
     let _com_init = ComInit::new();
 
-    let balance_xml_path = std::env::args().nth(1);
-    let gui_mode;
+    let args: Vec<String> = std::env::args().skip(1).collect();
+
+    if args.iter().any(|arg| arg == "-h" || arg == "--help") {
+        print_usage();
+        return;
+    }
+
+    if let Some(command) = args.first() {
+        if let Some(result) = run_command(command, &args[1..]) {
+            report_result(result, false);
+            return;
+        }
+    }
 
+    let (options, balance_xml_path) = match parse_options(&args) {
+        Ok(parsed) => parsed,
+        Err(e) => {
+            report_result(Err(e), false);
+            return;
+        }
+    };
+
+    let gui_mode;
     let balance_xml_path = match balance_xml_path {
         Some(path) => {
             gui_mode = false;
-            if path == "-h" || path == "--help" {
-                print_usage();
-                return;
-            } else {
-                path
-            }
+            path
         }
         None => {
             #[cfg(windows)]
@@ -272,7 +566,11 @@ fn main() {
         }
     };
 
-    match run(Path::new(&balance_xml_path), gui_mode) {
+    report_result(run(Path::new(&balance_xml_path), &options, gui_mode), gui_mode);
+}
+
+fn report_result(result: Result<(), String>, gui_mode: bool) {
+    match result {
         Ok(_) => {
             if gui_mode {
                 show_message_box("Complete", MessageType::Info);
@@ -285,6 +583,7 @@ fn main() {
                 show_message_box(&e, MessageType::Error);
             } else {
                 eprintln!("Error: {}", e);
+                std::process::exit(1);
             }
         }
     }
@@ -292,25 +591,301 @@ fn main() {
 
 fn print_usage() {
     eprintln!("Rise of Nations: Extended Edition OBJ_MASK bug workaround");
-    eprintln!("");
+    eprintln!();
     eprintln!("USAGE:");
-    eprintln!("    ron-objmask-workaround [balance file]");
-    eprintln!("");
-    eprintln!("OPTION:");
-    eprintln!("    -h, --help  Print this help information");
+    eprintln!("    ron-objmask-workaround [options] [balance file]");
+    eprintln!("    ron-objmask-workaround anomalies [options] <balance file>");
+    eprintln!("    ron-objmask-workaround classes [--unitrules <file>] [--combine <mode>] <balance file>");
+    eprintln!("    ron-objmask-workaround convert [--units] <input> <output>");
+    eprintln!("    ron-objmask-workaround compile <source file> [unitrules file]");
+    eprintln!("    ron-objmask-workaround counters [options] <balance file> <unit>");
+    eprintln!("    ron-objmask-workaround decompile <balance file>");
+    eprintln!("    ron-objmask-workaround evaluate [options] <balance file> [<attacker> <target>]...");
+    eprintln!("    ron-objmask-workaround factorize <balance file> [unitrules file]");
+    eprintln!("    ron-objmask-workaround fmt [options] <balance file>...");
+    eprintln!("    ron-objmask-workaround graph [options] <balance file>");
+    eprintln!("    ron-objmask-workaround impact [--unitrules <file>] [--top <count>] <balance file>");
+    eprintln!("    ron-objmask-workaround update [options] <old unitrules file> <new unitrules file>");
+    eprintln!("                                  <source balance file> <flattened balance file>");
+    eprintln!("    ron-objmask-workaround verify [--unitrules <file>] <generated balance file> <balance file>");
+    eprintln!();
+    eprintln!("COMMANDS:");
+    eprintln!("    anomalies   List flattened cells below --min <value> (default 20), above");
+    eprintln!("                --max <value> (default 500) or stacked from more than");
+    eprintln!("                --max-factors <count> (default 3) factors, with the factors");
+    eprintln!("                behind each. Takes --unitrules <file> and --combine <mode>.");
+    eprintln!("                With --ci, exit with an error if any cell is listed");
+    eprintln!("    classes     Group units that share an OBJ_MASK, and units whose flattened");
+    eprintln!("                rows and columns are identical");
+    eprintln!("    convert     Convert balance.xml to or from JSON, TOML or RON, chosen by");
+    eprintln!("                file extension. With --units, export the unit OBJ_MASK map");
+    eprintln!("                read from unitrules.xml instead. Requires the serde feature");
+    eprintln!("    compile     Compile a TOML balance source into a flattened balance file,");
+    eprintln!("                written to standard output. Requires the serde feature");
+    eprintln!("    counters    List the --top <count> (default 5) units the given unit is");
+    eprintln!("                strongest and weakest against, and the units strongest and");
+    eprintln!("                weakest against it, with the factors behind each flattened");
+    eprintln!("                modifier. Takes --unitrules <file> and --combine <mode>");
+    eprintln!("    decompile   Write a balance file as a TOML balance source to standard");
+    eprintln!("                output. Requires the serde feature");
+    eprintln!("    evaluate    Compare the intended modifier for each given unit pair with");
+    eprintln!("                what the game applies. Takes --unitrules <file>, --combine");
+    eprintln!("                <mode>, --all to list every pair that differs, and --engine");
+    eprintln!("                <model> where the model is ignore-objmasks (default),");
+    eprintln!("                ignore-rows, ignore-columns or correct");
+    eprintln!("    factorize   Recover objmask-level factors and per-unit overrides from a");
+    eprintln!("                flattened balance file, writing a source table to standard");
+    eprintln!("                output");
+    eprintln!("    fmt         Write a balance file back out in a canonical layout without");
+    eprintln!("                recalculating it. Takes --sort <order> where the order is");
+    eprintln!("                keep (default) or name, and the output layout options. Values");
+    eprintln!("                are written in their shortest form unless --keep-values is");
+    eprintln!("                given. With --check, list the files that are not in that");
+    eprintln!("                layout and exit with an error if there are any");
+    eprintln!("    graph       Write a Graphviz DOT graph to standard output, with an edge");
+    eprintln!("                from A to B wherever A's flattened modifier against B is above");
+    eprintln!("                --threshold <value> (default 150). Units are clustered by");
+    eprintln!("                their first OBJ_MASK flag. With --cycles, counter loops are");
+    eprintln!("                listed and their edges drawn in red. Also takes --unitrules");
+    eprintln!("                <file> and --combine <mode>");
+    eprintln!("    impact      Report, for each OBJ_MASK flag, the unit pairs whose modifier");
+    eprintln!("                is wrong because the game ignores the flag, with the largest");
+    eprintln!("                errors and the --top <count> (default 5) most affected units");
+    eprintln!("    update      Recalculate only the units whose OBJ_MASK was added, removed or");
+    eprintln!("                changed between two versions of unitrules.xml, writing the");
+    eprintln!("                updated flattened balance file to standard output. Uses the");
+    eprintln!("                options recorded in the flattened file's header, followed by");
+    eprintln!("                any options given");
+    eprintln!("    verify      Regenerate a flattened balance file from the balance file it");
+    eprintln!("                was generated from, using the options recorded in its header,");
+    eprintln!("                and check that the two match");
+    eprintln!();
+    eprintln!("A balance file of - is read from standard input.");
+    eprintln!();
+    eprintln!("OPTIONS:");
+    eprintln!("    --unitrules <file>");
+    eprintln!("                    Read this unitrules.xml rather than the one next to the");
+    eprintln!("                    balance file. Required when reading from standard input");
+    eprintln!("    --patch <file>  Apply a balance patch before flattening, may be given");
+    eprintln!("                    multiple times to apply patches in order");
+    eprintln!("    --overrides <file>");
+    eprintln!("                    Apply override rules to the flattened balance, may be");
+    eprintln!("                    given multiple times to apply files in order");
+    eprintln!("    --combine <mode>");
+    eprintln!("                    How modifiers applying to the same unit pair combine:");
+    eprintln!("                    multiply (default), add, strongest or direct, where");
+    eprintln!("                    direct lets a unit vs unit cell replace the objmask");
+    eprintln!("                    factors");
+    eprintln!("    --include-flags <flags>");
+    eprintln!("                    Only expand the given comma separated OBJ_MASK flags,");
+    eprintln!("                    by name or character code");
+    eprintln!("    --exclude-flags <flags>");
+    eprintln!("                    Do not expand the given comma separated OBJ_MASK flags,");
+    eprintln!("                    leaving them in the table as they were");
+    eprintln!("    --only <unit>   Only recalculate the rows and columns of units matching");
+    eprintln!("                    the name or pattern (* and ? wildcards), carrying all");
+    eprintln!("                    other cells over from the balance file. May be given");
+    eprintln!("                    multiple times");
+    eprintln!("    --only-flag <flag>");
+    eprintln!("                    As --only, for units with the given OBJ_MASK flag");
+    eprintln!("    --base <file>   With --only or --only-flag, carry cells over from this");
+    eprintln!("                    previously flattened balance file instead");
+    eprintln!("    --table <table> Process the TABLE with this name or position, counting");
+    eprintln!("                    from 1, instead of the first. May be given multiple times.");
+    eprintln!("                    Other tables are written back out unchanged");
+    eprintln!("    --merge <policy>");
+    eprintln!("                    How cells given more than once in the balance file,");
+    eprintln!("                    through a repeated ENTRY or attribute, are resolved:");
+    eprintln!("                    error, first, last (default) or multiply");
+    eprintln!("    --output-encoding <encoding>");
+    eprintln!("                    Write the new balance file as utf-8, utf-16le, utf-16be");
+    eprintln!("                    or windows-1252 rather than the encoding it was read in");
+    eprintln!("    --indent <space|tab>");
+    eprintln!("                    Character to indent the new balance file with, space by");
+    eprintln!("                    default");
+    eprintln!("    --indent-width <width>");
+    eprintln!("                    Number of indent characters per level, 2 by default");
+    eprintln!("    --attribute-per-line");
+    eprintln!("                    Write each attribute of an ENTRY on its own line");
+    eprintln!("    --crlf          End lines with CRLF, as in the game's own files");
+    eprintln!("    --compact       Write the new balance file without line breaks or");
+    eprintln!("                    indentation");
+    eprintln!("    -h, --help      Print this help information");
+}
+
+/// Options controlling how the new balance is produced.
+#[derive(Clone, Debug, Default)]
+struct Options {
+    /// unitrules.xml to read, rather than the one next to balance.xml.
+    unit_rules: Option<PathBuf>,
+    /// Patches applied in order to the balance table before it is
+    /// flattened.
+    patches: Vec<PathBuf>,
+    /// Override rules applied in order to the flattened balance.
+    overrides: Vec<PathBuf>,
+    balance: BalanceOptions,
+    /// Unit name patterns to recalculate, all other cells being carried
+    /// over from the input.
+    only_units: Vec<String>,
+    /// As `only_units`, selecting units by objmask flag.
+    only_flags: Vec<&'static str>,
+    /// Balance file to carry cells over from instead of the input.
+    base: Option<PathBuf>,
+    /// Encoding to write the output in, rather than that of the input.
+    output_encoding: Option<&'static Encoding>,
+    /// How repeated cells in the input balance file are resolved.
+    merge: MergePolicy,
+    /// Names or positions of the tables to process, the first table if
+    /// empty. Other tables are written back out as they were.
+    tables: Vec<String>,
+    format: OutputFormat,
+    /// The command line arguments other than the input files, recorded in
+    /// the provenance header.
+    arguments: Vec<String>,
+}
+
+fn parse_options(args: &[String]) -> Result<(Options, Option<String>), String> {
+    let mut options = Options::default();
+    let mut balance_xml_path = None;
+    let mut included_flags = None;
+    let mut excluded_flags = 0;
+
+    // Arguments naming the input files, which are left out of the options
+    // recorded in the output.
+    let mut input_indices = Vec::new();
+
+    let mut indexed_args = args.iter().enumerate();
+    while let Some((i, arg)) = indexed_args.next() {
+        let mut value = || {
+            indexed_args.next()
+                .map(|(_, value)| value)
+                .ok_or_else(|| format!("Missing value for option \"{}\"", arg))
+        };
+        match arg.as_str() {
+            "--unitrules" => {
+                options.unit_rules = Some(PathBuf::from(value()?));
+                input_indices.extend(&[i, i + 1]);
+            }
+            "--patch" => options.patches.push(PathBuf::from(value()?)),
+            "--overrides" => options.overrides.push(PathBuf::from(value()?)),
+            "--combine" => {
+                let name = value()?;
+                options.balance.combiner = combine::combiner_by_name(name)
+                    .ok_or_else(|| format!("Unknown combine mode \"{}\"", name))?;
+            }
+            "--include-flags" => *included_flags.get_or_insert(0) |= parse_flag_list(value()?)?,
+            "--exclude-flags" => excluded_flags |= parse_flag_list(value()?)?,
+            "--only" => options.only_units.push(value()?.clone()),
+            "--base" => options.base = Some(PathBuf::from(value()?)),
+            "--table" => options.tables.push(value()?.clone()),
+            "--merge" => {
+                let name = value()?;
+                options.merge = MergePolicy::from_name(name)
+                    .ok_or_else(|| format!("Unknown merge policy \"{}\"", name))?;
+            }
+            "--output-encoding" => options.output_encoding = Some(encoding::encoding_for_label(value()?)?),
+            "--only-flag" => {
+                let flag = value()?;
+                options.only_flags.push(objmask_name_to_attrib_str(flag)
+                    .ok_or_else(|| format!("Unknown OBJ_MASK flag \"{}\"", flag))?);
+            }
+            _ if options.format.parse_option(arg, &mut value)? => (),
+            _ if arg.starts_with('-') && arg != "-" => return Err(format!("Unknown option \"{}\"", arg)),
+            _ if balance_xml_path.is_none() => {
+                balance_xml_path = Some(arg.clone());
+                input_indices.push(i);
+            }
+            _ => return Err(format!("Unexpected argument \"{}\"", arg)),
+        }
+    }
+
+    options.arguments = args.iter().enumerate()
+        .filter(|(i, _)| !input_indices.contains(i))
+        .map(|(_, arg)| arg.clone())
+        .collect();
+
+    options.balance.expanded_flags = included_flags.unwrap_or(!0) & !excluded_flags;
+
+    options.format.validate()?;
+
+    if options.base.is_some() && options.only_units.is_empty() && options.only_flags.is_empty() {
+        return Err("--base requires --only or --only-flag".to_owned());
+    }
+
+    Ok((options, balance_xml_path))
+}
+
+/// Parse a comma separated list of objmask flags into a bit set of
+/// `OBJMASK_INFO` indices.
+fn parse_flag_list(flags: &str) -> Result<u32, String> {
+    let mut flag_set = 0;
+    for flag in flags.split(',').map(str::trim).filter(|flag| !flag.is_empty()) {
+        let attrib = objmask_name_to_attrib_str(flag)
+            .ok_or_else(|| format!("Unknown OBJ_MASK flag \"{}\"", flag))?;
+        let i = OBJMASK_INFO.iter().position(|&(_, name)| name == attrib).unwrap();
+        flag_set |= 1 << i;
+    }
+
+    Ok(flag_set)
+}
+
+fn run_command(command: &str, args: &[String]) -> Option<Result<(), String>> {
+    let result = match command {
+        #[cfg(feature = "serde")]
+        "convert" => convert::run_convert(args),
+        #[cfg(not(feature = "serde"))]
+        "convert" => Err("The convert command requires the \"serde\" feature".to_owned()),
+        #[cfg(feature = "serde")]
+        "compile" => source::run_compile(args),
+        #[cfg(feature = "serde")]
+        "decompile" => source::run_decompile(args),
+        #[cfg(not(feature = "serde"))]
+        "compile" | "decompile" => Err(format!("The {} command requires the \"serde\" feature", command)),
+        "anomalies" => anomalies::run_anomalies(args),
+        "classes" => classes::run_classes(args),
+        "counters" => counters::run_counters(args),
+        "evaluate" => engine::run_evaluate(args),
+        "factorize" => factorize::run_factorize(args),
+        "fmt" => fmt::run_fmt(args),
+        "graph" => graph::run_graph(args),
+        "impact" => impact::run_impact(args),
+        "update" => update::run_update(args),
+        "verify" => provenance::run_verify(args),
+        _ => return None,
+    };
+
+    Some(result)
+}
+
+/// Balance entries that are not units in unitrules.xml, such as whole
+/// categories of buildings and ages.
+const META_ENTRIES: [&str; 15] = [
+    "SIEGE", "FORTS", "TOWERS", "CITIES", "OBSPOST", "BUILDINGS", "UNITS",
+    "AGE_0", "AGE_1", "AGE_2", "AGE_3", "AGE_4", "AGE_5", "AGE_6", "AGE_7",
+];
+
+/// Iterate over the units in unitrules.xml, leaving out meta entries.
+fn units(unit_objmask_map: &UnitObjmaskMap) -> impl Iterator<Item = &String> {
+    unit_objmask_map.keys().filter(|unit| !META_ENTRIES.contains(&unit.as_str()))
 }
 
-fn run(balance_xml_path: &Path, gui_mode: bool) -> Result<(), String> {
+/// The unitrules.xml file next to a balance file.
+fn sibling_unit_rules_path(balance_xml_path: &Path) -> Result<PathBuf, String> {
+    if balance_xml_path == Path::new("-") {
+        return Err("--unitrules is required when balance.xml is read from standard input".to_owned());
+    }
+
     let ron_data_path = balance_xml_path.parent()
         .ok_or_else(|| "No parent directory found".to_owned())?;
 
-    let unit_rules_path = ron_data_path.join("unitrules.xml");
+    Ok(ron_data_path.join("unitrules.xml"))
+}
 
-    let unit_objmask_map = parse_unitrules(&unit_rules_path)?;
-    let old_unit_balance = parse_balance(balance_xml_path)?;
-    let new_unit_balance = calculate_new_balance(&unit_objmask_map, &old_unit_balance);
+fn run(balance_xml_path: &Path, options: &Options, gui_mode: bool) -> Result<(), String> {
+    let (document, input_encoding) = flatten_document(balance_xml_path, options, gui_mode)?;
 
-    let result = if gui_mode {
+    let output_encoding = options.output_encoding.unwrap_or(input_encoding);
+    if gui_mode {
         let new_balance_xml_path = match show_file_dialog(true /* saving */) {
             Some(path) => path,
             None => return Ok(()),
@@ -318,144 +893,504 @@ fn run(balance_xml_path: &Path, gui_mode: bool) -> Result<(), String> {
         let balance_xml_file = File::create(new_balance_xml_path)
             .map_err(|e| format!("{}", e))?;
         let mut balance_xml_writer = BufWriter::new(balance_xml_file);
-        write_new_balance(&mut balance_xml_writer, &new_unit_balance)
+        write_new_document(&mut balance_xml_writer, &document, output_encoding, &options.format)
     } else {
-        write_new_balance(&mut std::io::stdout(), &new_unit_balance)
+        write_new_document(&mut std::io::stdout(), &document, output_encoding, &options.format)
+    }
+}
+
+/// Flatten the selected tables of a balance file, recording the inputs
+/// and options in its provenance header. Returns the document and the
+/// encoding it was read in.
+fn flatten_document(balance_xml_path: &Path, options: &Options,
+                    gui_mode: bool) -> Result<(BalanceDocument, &'static Encoding), String> {
+    let unit_rules_path = match &options.unit_rules {
+        Some(unit_rules_path) => unit_rules_path.clone(),
+        None => sibling_unit_rules_path(balance_xml_path)?,
     };
 
-    result.map_err(|e| format!("Failed to write new balance.xml file: {}", e))?;
+    // The files are read once and parsed from memory, as standard input
+    // cannot be read twice.
+    let unit_rules_bytes = encoding::read_file(&unit_rules_path, "unitrules.xml")?;
+    let (unit_rules_xml, _) = encoding::decode_xml(&unit_rules_bytes, "unitrules.xml")?;
+    let unit_objmask_map = parse_unitrules_xml(&unit_rules_xml)?;
 
-    Ok(())
+    let balance_bytes = encoding::read_file(balance_xml_path, "balance.xml")?;
+    let (balance_xml, input_encoding) = encoding::decode_xml(&balance_bytes, "balance.xml")?;
+    let mut document = parse_balance_xml(&balance_xml, options.merge)?;
+    document.utf8_bom = encoding::has_utf8_bom(&balance_bytes);
+
+    let table_indices = document.select_tables(&options.tables)?;
+
+    let patches = options.patches.iter()
+        .map(|patch_path| patch::parse_patch(patch_path))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let overrides = options.overrides.iter()
+        .map(|overrides_path| overrides::parse_overrides(overrides_path))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let selected = if !options.only_units.is_empty() || !options.only_flags.is_empty() {
+        let selected = subset::select_units(&unit_objmask_map, &options.only_units, &options.only_flags);
+        if selected.is_empty() {
+            return Err("No units match the --only and --only-flag selections".to_owned());
+        }
+
+        Some(selected)
+    } else {
+        None
+    };
+
+    let base_bytes = match &options.base {
+        Some(base_path) => Some(encoding::read_file(base_path, "base balance file")?),
+        None => None,
+    };
+    let base_document = match &base_bytes {
+        Some(base_bytes) => {
+            let (base_xml, _) = encoding::decode_xml(base_bytes, "base balance file")?;
+            Some(parse_balance_xml(&base_xml, MergePolicy::default())?)
+        }
+        None => None,
+    };
+
+    for table_index in table_indices {
+        if document.tables.len() > 1 {
+            eprintln!("Processing {}", document.describe_table(table_index));
+        }
+
+        // Carry cells over from the base table with the same name, or in
+        // the same position if it has none.
+        let base_unit_balance = match &base_document {
+            Some(base_document) => {
+                let base_table_index = base_document.matching_table(&document, table_index).ok_or_else(|| {
+                    format!("No {} in the base balance file", document.describe_table(table_index))
+                })?;
+                Some(base_document.tables[base_table_index].unit_balance.clone())
+            }
+            None => None,
+        };
+
+        let table = &mut document.tables[table_index];
+        table.unit_balance = flatten_table(&unit_objmask_map, table.unit_balance.clone(), base_unit_balance,
+                                           &patches, &overrides, &options.balance, selected.as_ref(), gui_mode)?;
+    }
+
+    document.header = provenance::header(&balance_bytes, &unit_rules_bytes, &patches, &overrides, base_bytes.as_deref(),
+                                         &unit_objmask_map, options);
+
+    Ok((document, input_encoding))
+}
+
+/// Patch, flatten and override one balance table. If only some units are
+/// `selected`, cells of other units are carried over from
+/// `base_unit_balance`, or the table itself if there is no base.
+#[allow(clippy::too_many_arguments)]
+fn flatten_table(unit_objmask_map: &UnitObjmaskMap, mut old_unit_balance: UnitBalance,
+                 base_unit_balance: Option<UnitBalance>, patches: &[patch::BalancePatch],
+                 overrides: &[overrides::BalanceOverrides], options: &BalanceOptions,
+                 selected: Option<&FnvHashSet<String>>, gui_mode: bool) -> Result<UnitBalance, String> {
+    let base_unit_balance = base_unit_balance.unwrap_or_else(|| old_unit_balance.clone());
+
+    if !patches.is_empty() {
+        let overlaps = patch::apply_patches(&mut old_unit_balance, patches);
+        for overlap in &overlaps {
+            eprintln!("Warning: {}", overlap);
+        }
+
+        if gui_mode && !overlaps.is_empty() {
+            show_message_box(&overlaps.join("\n"), MessageType::Warning);
+        }
+    }
+
+    let mut new_unit_balance = calculate_new_balance(unit_objmask_map, &old_unit_balance, options);
+
+    // Overrides are applied to the full recalculation so that carried over
+    // cells are checked against the overridden values, but only the cells
+    // that are kept are reported.
+    for overrides in overrides {
+        for applied in overrides::apply_overrides(unit_objmask_map, &mut new_unit_balance, overrides) {
+            let kept = selected
+                .is_none_or(|selected| selected.contains(&applied.attacker) || selected.contains(&applied.target));
+            if kept {
+                eprintln!("Override: {}", applied);
+            }
+        }
+    }
+
+    if let Some(selected) = selected {
+        eprintln!("Keeping recalculated values for {} selected units", selected.len());
+
+        let (merged_unit_balance, inconsistencies) =
+            subset::merge_selected_units(&new_unit_balance, &base_unit_balance, selected);
+        if !inconsistencies.is_empty() {
+            let warning = format!("{} cells carried over from the base balance file differ from a full \
+                                   recalculation", inconsistencies.len());
+            eprintln!("Warning: {}, including:", warning);
+            for inconsistency in inconsistencies.iter().take(10) {
+                eprintln!("    {}", inconsistency);
+            }
+
+            if gui_mode {
+                show_message_box(&warning, MessageType::Warning);
+            }
+        }
+
+        new_unit_balance = merged_unit_balance;
+    }
+
+    Ok(new_unit_balance)
 }
 
-fn parse_unitrules(unitrules_path: &Path) -> Result<FnvIndexMap<String, FnvHashSet<&'static str>>, String> {
-    let unitrules_xml_file = File::open(unitrules_path)
-        .map_err(|e| format!("Failed to open unitrules.xml: {}", e))?;
-    let unitrules_xml_reader = BufReader::new(unitrules_xml_file);
+/// The NAME and OBJ_MASK of a UNIT element in unitrules.xml.
+#[derive(Debug, Default)]
+struct UnitRecord {
+    name: Option<String>,
+    obj_mask: Option<String>,
+}
 
-    let mut unitrules_xml_document = Reader::from_reader(unitrules_xml_reader);
+#[derive(Clone, Copy, Debug)]
+enum UnitField {
+    Name,
+    ObjMask,
+}
+
+impl UnitField {
+    fn from_name(name: &[u8]) -> Option<UnitField> {
+        if name.eq_ignore_ascii_case(b"NAME") {
+            Some(UnitField::Name)
+        } else if name.eq_ignore_ascii_case(b"OBJ_MASK") {
+            Some(UnitField::ObjMask)
+        } else {
+            None
+        }
+    }
+}
+
+impl UnitRecord {
+    fn set(&mut self, field: UnitField, value: &str) {
+        let value = Some(value.trim().to_owned());
+        match field {
+            UnitField::Name => self.name = value,
+            UnitField::ObjMask => self.obj_mask = value,
+        }
+    }
+
+    /// Read the NAME and OBJ_MASK attributes of a UNIT element, for the
+    /// variant of the format that uses attributes rather than children.
+    fn read_attributes<B: std::io::BufRead>(&mut self, element: &BytesStart,
+                                            reader: &Reader<B>) -> Result<(), String> {
+        for attrib in element.attributes() {
+            let attrib = attrib
+                .map_err(|e| format!("Failed to get attribute in a UNIT element: {}", e))?;
+            if let Some(field) = UnitField::from_name(attrib.key) {
+                let value = attrib.unescape_and_decode_value(reader)
+                    .map_err(|e| format!("Failed to get attribute value in a UNIT element: {}", e))?;
+                self.set(field, &value);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn parse_unitrules(unitrules_path: &Path) -> Result<UnitObjmaskMap, String> {
+    let (unitrules_xml, _) = encoding::read_xml_file(unitrules_path, "unitrules.xml")?;
+    parse_unitrules_xml(&unitrules_xml)
+}
+
+fn parse_unitrules_xml(unitrules_xml: &str) -> Result<UnitObjmaskMap, String> {
+    let mut unitrules_xml_document = Reader::from_str(unitrules_xml);
 
     eprintln!("Processing unitrules.xml");
 
-    let mut unit_objmask_map: FnvIndexMap<String, FnvHashSet<&'static str>> = FnvIndexMap::default();
+    let mut unit_objmask_map = UnitObjmaskMap::default();
 
+    // Only the text of direct NAME and OBJ_MASK children of a UNIT element
+    // is used, so track the depth of the UNIT element being read.
     let mut buf = Vec::new();
-    let mut in_unit_element = false;
-    let mut in_name_element = false;
-    let mut in_obj_mask_element = false;
-    let mut cur_unit_name = String::new();
-    let mut cur_obj_mask = String::new();
+    let mut depth = 0;
+    let mut unit_depth = None;
+    let mut unit = UnitRecord::default();
+    let mut field = None;
+    let mut text = String::new();
     loop {
         let event = unitrules_xml_document.read_event(&mut buf)
             .map_err(|e| format!("Failed to read unitrules.xml: {}", e))?;
         match event {
-            Event::Start(e) if e.name() == b"UNIT" => {
-                in_unit_element = true;
-            }
-            Event::Start(e) if e.name() == b"NAME" && in_unit_element => {
-                in_name_element = true;
-            }
-            Event::Start(e) if e.name() == b"OBJ_MASK" && in_unit_element => {
-                in_obj_mask_element = true;
-            }
-            Event::Text(e) if in_name_element => {
-                cur_unit_name = e.unescape_and_decode(&unitrules_xml_document)
-                    .map_err(|e| format!("Failed to get unit name: {}", e))?;
-                cur_unit_name = cur_unit_name.replace(" ", "_").replace("'", "");
-            }
-            Event::Text(e) if in_obj_mask_element => {
-                cur_obj_mask = e.unescape_and_decode(&unitrules_xml_document)
-                .map_err(|e| format!("Failed to get unit obj_mask: {}", e))?;
-            }
-            Event::End(e) if e.name() == b"NAME" && in_unit_element => {
-                in_name_element = false;
-            }
-            Event::End(e) if e.name() == b"OBJ_MASK" && in_unit_element => {
-                in_obj_mask_element = false;
+            Event::Start(e) => {
+                depth += 1;
+                match unit_depth {
+                    None if e.name() == b"UNIT" => {
+                        unit_depth = Some(depth);
+                        unit.read_attributes(&e, &unitrules_xml_document)?;
+                    }
+                    Some(unit_depth) if depth == unit_depth + 1 => {
+                        field = UnitField::from_name(e.name());
+                        text.clear();
+                    }
+                    _ => (),
+                }
             }
-            Event::End(e) if e.name() == b"UNIT" => {
-                in_unit_element = false;
-
-                if UNIT_IGNORE_LIST.contains(&cur_unit_name.as_str()) {
-                    // Ignore this unit entry.
-                    cur_unit_name.clear();
-                    cur_obj_mask.clear();
-                    continue;
+            Event::Empty(e) => match unit_depth {
+                None if e.name() == b"UNIT" => {
+                    let mut unit = UnitRecord::default();
+                    unit.read_attributes(&e, &unitrules_xml_document)?;
+                    add_unit(&mut unit_objmask_map, unit);
                 }
-
-                let mut obj_masks = FnvHashSet::<&'static str>::default();
-                for c in cur_obj_mask.chars() {
-                    if let Some(name) = char_to_attrib_str(c) {
-                        obj_masks.insert(name);
-                    } else {
-                        eprintln!("Warning: unknown OBJ_MASK flag found '{}'", c);
+                Some(unit_depth) if depth == unit_depth => {
+                    if let Some(field) = UnitField::from_name(e.name()) {
+                        unit.set(field, "");
                     }
                 }
-
-                use indexmap::map::Entry;
-                match unit_objmask_map.entry(cur_unit_name.clone()) {
-                    Entry::Vacant(v) => {
-                        v.insert(obj_masks);
-                    }
-                    Entry::Occupied(mut o) => {
-                        if *o.get() != obj_masks {
-                            eprintln!("Warning: different units with identical names have differing OBJ_MASK values");
+                _ => (),
+            },
+            Event::Text(e) if field.is_some() && unit_depth.map(|unit_depth| unit_depth + 1) == Some(depth) => {
+                let value = e.unescape_and_decode(&unitrules_xml_document)
+                    .map_err(|e| format!("Failed to get UNIT element text: {}", e))?;
+                text.push_str(&value);
+            }
+            Event::CData(e) if field.is_some() && unit_depth.map(|unit_depth| unit_depth + 1) == Some(depth) => {
+                let value = unitrules_xml_document.decode(e.escaped())
+                    .map_err(|e| format!("Failed to get UNIT element text: {}", e))?;
+                text.push_str(value);
+            }
+            Event::End(_) => {
+                match unit_depth {
+                    Some(unit_depth) if depth == unit_depth + 1 => {
+                        if let Some(field) = field.take() {
+                            unit.set(field, &text);
                         }
-                        o.get_mut().extend(obj_masks.iter());
                     }
+                    Some(unit_depth) if depth == unit_depth => {
+                        add_unit(&mut unit_objmask_map, std::mem::take(&mut unit));
+                    }
+                    _ => (),
                 }
 
-                cur_unit_name.clear();
-                cur_obj_mask.clear();
+                if unit_depth == Some(depth) {
+                    unit_depth = None;
+                }
+                depth -= 1;
             }
             Event::Eof => break,
             _ => (),
         }
+
+        buf.clear();
     }
 
     // Add some additional "meta" entries.
-    unit_objmask_map.insert("SIEGE".to_owned(), Default::default());
-    unit_objmask_map.insert("FORTS".to_owned(), Default::default());
-    unit_objmask_map.insert("TOWERS".to_owned(), Default::default());
-    unit_objmask_map.insert("CITIES".to_owned(), Default::default());
-    unit_objmask_map.insert("OBSPOST".to_owned(), Default::default());
-    unit_objmask_map.insert("BUILDINGS".to_owned(), Default::default());
-    unit_objmask_map.insert("UNITS".to_owned(), Default::default());
-    unit_objmask_map.insert("AGE_0".to_owned(), Default::default());
-    unit_objmask_map.insert("AGE_1".to_owned(), Default::default());
-    unit_objmask_map.insert("AGE_2".to_owned(), Default::default());
-    unit_objmask_map.insert("AGE_3".to_owned(), Default::default());
-    unit_objmask_map.insert("AGE_4".to_owned(), Default::default());
-    unit_objmask_map.insert("AGE_5".to_owned(), Default::default());
-    unit_objmask_map.insert("AGE_6".to_owned(), Default::default());
-    unit_objmask_map.insert("AGE_7".to_owned(), Default::default());
+    for meta_entry in META_ENTRIES.iter() {
+        unit_objmask_map.insert(meta_entry.to_string(), Default::default());
+    }
 
     Ok(unit_objmask_map)
 }
 
+fn add_unit(unit_objmask_map: &mut UnitObjmaskMap, unit: UnitRecord) {
+    let unit_name = match unit.name {
+        Some(name) if !name.is_empty() => name.replace(" ", "_").replace("'", ""),
+        _ => {
+            eprintln!("Warning: UNIT element without a NAME skipped");
+            return;
+        }
+    };
+
+    if UNIT_IGNORE_LIST.contains(&unit_name.as_str()) {
+        // Ignore this unit entry.
+        return;
+    }
+
+    let mut obj_masks = FnvHashSet::<&'static str>::default();
+    for c in unit.obj_mask.unwrap_or_default().chars().filter(|c| !c.is_whitespace()) {
+        if let Some(name) = char_to_attrib_str(c) {
+            obj_masks.insert(name);
+        } else {
+            eprintln!("Warning: unknown OBJ_MASK flag found '{}'", c);
+        }
+    }
+
+    use indexmap::map::Entry;
+    match unit_objmask_map.entry(unit_name) {
+        Entry::Vacant(v) => {
+            v.insert(obj_masks);
+        }
+        Entry::Occupied(mut o) => {
+            if *o.get() != obj_masks {
+                eprintln!("Warning: different units with identical names have differing OBJ_MASK values");
+            }
+            o.get_mut().extend(obj_masks.iter());
+        }
+    }
+}
+
+/// How to resolve balance cells that are given more than once, through
+/// either a repeated attribute or a repeated ENTRY.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+enum MergePolicy {
+    Error,
+    First,
+    #[default]
+    Last,
+    Multiply,
+}
+
+impl MergePolicy {
+    fn from_name(name: &str) -> Option<MergePolicy> {
+        match name {
+            "error" => Some(MergePolicy::Error),
+            "first" => Some(MergePolicy::First),
+            "last" => Some(MergePolicy::Last),
+            "multiply" => Some(MergePolicy::Multiply),
+            _ => None,
+        }
+    }
+
+    fn merge_modifier(self, first: f32, last: f32) -> f32 {
+        match self {
+            MergePolicy::First => first,
+            MergePolicy::Error | MergePolicy::Last => last,
+            MergePolicy::Multiply => first * last / 100.0,
+        }
+    }
+
+    /// Merge `entry` into `merged_entry`, returning the names of the cells
+    /// that were in both. Values that are not numbers cannot be
+    /// multiplied, so the last one is kept, including where only one of
+    /// the values is a number.
+    fn merge_entry(self, merged_entry: &mut UnitBalanceEntry, entry: UnitBalanceEntry) -> Vec<String> {
+        let mut duplicates = Vec::new();
+        for (key, modifier) in entry.modifiers {
+            if merged_entry.passthrough.contains_key(&key) {
+                duplicates.push(key.clone());
+                if self != MergePolicy::First {
+                    merged_entry.passthrough.shift_remove(&key);
+                    merged_entry.modifiers.insert(key, modifier);
+                }
+                continue;
+            }
+
+            use indexmap::map::Entry;
+            match merged_entry.modifiers.entry(key) {
+                Entry::Vacant(v) => {
+                    v.insert(modifier);
+                }
+                Entry::Occupied(mut o) => {
+                    duplicates.push(o.key().clone());
+                    let merged = self.merge_modifier(*o.get(), modifier);
+                    o.insert(merged);
+                }
+            }
+        }
+        for (key, value) in entry.passthrough {
+            if merged_entry.passthrough.contains_key(&key) || merged_entry.modifiers.contains_key(&key) {
+                duplicates.push(key.clone());
+                if self == MergePolicy::First {
+                    continue;
+                }
+            }
+            merged_entry.modifiers.shift_remove(&key);
+            merged_entry.lexical.shift_remove(&key);
+            merged_entry.passthrough.insert(key, value);
+        }
+        for (key, lexical) in entry.lexical {
+            // The first value is kept, whether or not it was a number.
+            if self == MergePolicy::First && (merged_entry.lexical.contains_key(&key)
+                || merged_entry.passthrough.contains_key(&key)) {
+                continue;
+            }
+            merged_entry.lexical.insert(key, lexical);
+        }
+        if !entry.children.is_empty() && (merged_entry.children.is_empty() || self != MergePolicy::First) {
+            merged_entry.children = entry.children;
+        }
+
+        duplicates
+    }
+}
+
+/// Parse the first table of a balance file.
 fn parse_balance(balance_xml_path: &Path) -> Result<UnitBalance, String> {
-    let balance_xml_file = File::open(balance_xml_path)
-        .map_err(|e| format!("Failed to open balance.xml: {}", e))?;
-    let balance_xml_reader = BufReader::new(balance_xml_file);
+    let (document, _) = parse_balance_document(balance_xml_path, MergePolicy::default())?;
+    if document.tables.len() > 1 {
+        eprintln!("Warning: balance.xml has {} tables, only the first is used", document.tables.len());
+    }
+
+    Ok(document.tables.into_iter().next().map(|table| table.unit_balance).unwrap_or_default())
+}
+
+/// Parse every table of a balance file, resolving repeated cells with
+/// `merge`, and return the encoding the file was in.
+fn parse_balance_document(balance_xml_path: &Path,
+                          merge: MergePolicy) -> Result<(BalanceDocument, &'static Encoding), String> {
+    let balance_bytes = encoding::read_file(balance_xml_path, "balance.xml")?;
+    let (balance_xml, encoding) = encoding::decode_xml(&balance_bytes, "balance.xml")?;
+    let mut document = parse_balance_xml(&balance_xml, merge)?;
+    document.utf8_bom = encoding::has_utf8_bom(&balance_bytes);
+    Ok((document, encoding))
+}
 
-    let mut balance_xml_document = Reader::from_reader(balance_xml_reader);
+fn parse_balance_xml(balance_xml: &str, merge: MergePolicy) -> Result<BalanceDocument, String> {
+    let mut balance_xml_document = Reader::from_str(balance_xml);
 
     eprintln!("Processing balance.xml");
 
-    let mut old_unit_balance = UnitBalance::default();
+    let mut document = BalanceDocument::default();
+    let mut current_table = None;
+    let mut root_seen = false;
+    // Kept content of each table, with the index of the entry it comes
+    // before, until the entries are all known.
+    let mut table_kept = Vec::new();
+    let mut invalid_cells = Vec::new();
+    let mut duplicates = Vec::new();
+    let mut entry_lines = FnvHashMap::default();
+
+    // Line numbers for messages, counted up to the latest ENTRY.
+    let mut line = 1;
+    let mut line_position = 0;
 
     let mut buf = Vec::new();
     loop {
+        let position = balance_xml_document.buffer_position();
         let event = balance_xml_document.read_event(&mut buf)
             .map_err(|e| format!("Failed to read balance.xml: {}", e))?;
+        let has_children = matches!(event, Event::Start(_));
         match event {
+            Event::Start(e) | Event::Empty(e) if e.name() == b"TABLE" => {
+                let mut attributes = Vec::new();
+                for attrib in e.attributes() {
+                    let attrib = attrib
+                        .map_err(|e| format!("Failed to get attribute in a balance TABLE element: {}", e))?;
+                    let key = balance_xml_document.decode(attrib.key)
+                        .map_err(|e| format!("Failed to get attribute key in a balance TABLE element: {}", e))?
+                        .to_owned();
+                    let value = attrib.unescape_and_decode_value(&balance_xml_document)
+                        .map_err(|e| format!("Failed to get attribute value in a balance TABLE element: {}", e))?;
+                    attributes.push((key, value));
+                }
+
+                document.tables.push(BalanceTable { attributes, ..BalanceTable::default() });
+                current_table = if has_children { Some(document.tables.len() - 1) } else { None };
+            }
+            Event::End(e) if e.name() == b"TABLE" => {
+                current_table = None;
+            }
             Event::Start(e) | Event::Empty(e) if e.name() == b"ENTRY" => {
+                line += balance_xml.as_bytes()[line_position..position].iter().filter(|&&c| c == b'\n').count();
+                line_position = position;
+
                 let mut name = String::new();
-                let mut modifiers = FnvIndexMap::default();
-                for attrib in e.attributes() {
+                let mut values = Vec::new();
+                // Repeated attributes are checked for below, with their
+                // names decoded.
+                for attrib in e.attributes().with_checks(false) {
                     let attrib = attrib
                         .map_err(|e| format!("Failed to get attribute in a balance ENTRY element: {}", e))?;
                     if attrib.key == b"name" {
+                        if !name.is_empty() {
+                            return Err(format!("Repeated \"name\" attribute in the balance ENTRY element on line {}",
+                                               line));
+                        }
                         name = attrib.unescape_and_decode_value(&balance_xml_document)
                             .map_err(|e| format!("Failed to get balance ENTRY element name: {}", e))?;
                     } else {
@@ -467,12 +1402,10 @@ fn parse_balance(balance_xml_path: &Path) -> Result<UnitBalance, String> {
                             .map_err(|e| format!("Failed to get attribute value in a balance ENTRY element: {}", e))?;
 
                         let value = balance_xml_document.decode(&value)
-                            .map_err(|e| format!("Failed to get attribute value in a balance ENTRY element: {}", e))?;
-
-                        let value = value.parse::<f32>()
-                            .map_err(|e| format!("Failed to parse attribute value in a balance ENTRY element: {}", e))?;
+                            .map_err(|e| format!("Failed to get attribute value in a balance ENTRY element: {}", e))?
+                            .to_owned();
 
-                        modifiers.insert(key, value);
+                        values.push((key, value));
                     }
                 }
 
@@ -480,7 +1413,126 @@ fn parse_balance(balance_xml_path: &Path) -> Result<UnitBalance, String> {
                     return Err("No \"name\" attribute found in a balance ENTRY element".to_owned());
                 }
 
-                old_unit_balance.entries.insert(name, UnitBalanceEntry { modifiers });
+                let mut entry = UnitBalanceEntry::default();
+                for (key, value) in values {
+                    let mut attribute = UnitBalanceEntry::default();
+                    match parse_modifier(&value) {
+                        Ok(Some((modifier, lenient))) => {
+                            if lenient {
+                                eprintln!("Warning: {} vs {} value \"{}\" read as {}", name, key, value, modifier);
+                            } else {
+                                attribute.lexical.insert(key.clone(), (modifier, value));
+                            }
+                            attribute.modifiers.insert(key, modifier);
+                        }
+                        Ok(None) if OBJMASK_INFO.iter().any(|(_, attrib)| *attrib == key) => {
+                            invalid_cells.push(format!("{} vs {}: \"{}\" is not a number", name, key, value));
+                        }
+                        Ok(None) => {
+                            attribute.passthrough.insert(key, value);
+                        }
+                        Err(e) => invalid_cells.push(format!("{} vs {}: {}", name, key, e)),
+                    }
+
+                    for key in merge.merge_entry(&mut entry, attribute) {
+                        duplicates.push(format!("Attribute {} is repeated in ENTRY \"{}\" on line {}", key, name, line));
+                    }
+                }
+
+                if has_children {
+                    // Keep whatever is inside the ENTRY element as it is.
+                    let content_start = balance_xml_document.buffer_position();
+                    let mut content_buf = Vec::new();
+                    let mut depth = 0;
+                    let content_end = loop {
+                        let content_end = balance_xml_document.buffer_position();
+                        match balance_xml_document.read_event(&mut content_buf) {
+                            Ok(Event::Start(_)) => depth += 1,
+                            Ok(Event::End(_)) if depth == 0 => break content_end,
+                            Ok(Event::End(_)) => depth -= 1,
+                            Ok(Event::Eof) => return Err(format!("Unclosed ENTRY element on line {}", line)),
+                            Ok(_) => (),
+                            Err(e) => return Err(format!("Failed to read balance.xml: {}", e)),
+                        }
+                        content_buf.clear();
+                    };
+
+                    let content = &balance_xml[content_start..content_end];
+                    if !content.trim().is_empty() {
+                        entry.children = content.to_owned();
+                    }
+                }
+
+                // ENTRY elements outside of any TABLE are kept together in
+                // an implicit one.
+                let table_index = match current_table {
+                    Some(table_index) => table_index,
+                    None => {
+                        document.tables.push(BalanceTable { implicit: true, ..BalanceTable::default() });
+                        current_table = Some(document.tables.len() - 1);
+                        document.tables.len() - 1
+                    }
+                };
+                let unit_balance = &mut document.tables[table_index].unit_balance;
+
+                use indexmap::map::Entry;
+                match unit_balance.entries.entry(name) {
+                    Entry::Vacant(v) => {
+                        entry_lines.insert((table_index, v.key().clone()), line);
+                        v.insert(entry);
+                    }
+                    Entry::Occupied(mut o) => {
+                        let keys = merge.merge_entry(o.get_mut(), entry);
+                        let first_line = entry_lines[&(table_index, o.key().clone())];
+                        duplicates.push(format!("ENTRY \"{}\" on line {} repeats the one on line {}{}", o.key(), line,
+                                                first_line, describe_repeated_cells(&keys)));
+                    }
+                }
+            }
+            Event::Start(e) | Event::Empty(e) if !root_seen => {
+                root_seen = true;
+                for attrib in e.attributes() {
+                    let attrib = attrib
+                        .map_err(|e| format!("Failed to get attribute in the balance.xml root element: {}", e))?;
+                    let key = balance_xml_document.decode(attrib.key)
+                        .map_err(|e| format!("Failed to get attribute key in the balance.xml root element: {}", e))?
+                        .to_owned();
+                    let value = attrib.unescape_and_decode_value(&balance_xml_document)
+                        .map_err(|e| format!("Failed to get attribute value in the balance.xml root element: {}",
+                                             e))?;
+                    document.root_attributes.push((key, value));
+                }
+            }
+            Event::Comment(_) | Event::PI(_) | Event::DocType(_) | Event::CData(_) | Event::Start(_)
+            | Event::Empty(_) => {
+                if let Event::Start(e) = &event {
+                    let name = e.name().to_owned();
+                    balance_xml_document.read_to_end(&name, &mut Vec::new())
+                        .map_err(|e| format!("Failed to read balance.xml: {}", e))?;
+                }
+                let content = balance_xml[position..balance_xml_document.buffer_position()].to_owned();
+
+                match current_table {
+                    _ if !root_seen => {
+                        let header = match &event {
+                            Event::Comment(e) if document.header.is_empty() => {
+                                let comment = balance_xml_document.decode(e)
+                                    .map_err(|e| format!("Failed to read a comment in balance.xml: {}", e))?;
+                                provenance::parse_header(comment)
+                            }
+                            _ => None,
+                        };
+                        match header {
+                            Some(header) => document.header = header,
+                            None => document.prolog.push(content),
+                        }
+                    }
+                    Some(table_index) => {
+                        let entry_index = document.tables[table_index].unit_balance.entries.len();
+                        table_kept.push((table_index, entry_index, content));
+                    }
+                    None => document.kept.push((document.tables.len(), content)),
+                }
             }
             Event::Eof => break,
             _ => (),
@@ -489,37 +1541,121 @@ fn parse_balance(balance_xml_path: &Path) -> Result<UnitBalance, String> {
         buf.clear();
     }
 
-    Ok(old_unit_balance)
+    for (table_index, entry_index, content) in table_kept {
+        let table = &mut document.tables[table_index];
+        let before = table.unit_balance.entries.get_index(entry_index).map(|(name, _)| name.clone());
+        table.kept.push((before, content));
+    }
+
+    if !invalid_cells.is_empty() {
+        return Err(format!("Found {} invalid values in balance.xml:\n    {}", invalid_cells.len(),
+                           invalid_cells.join("\n    ")));
+    }
+
+    if !duplicates.is_empty() {
+        if merge == MergePolicy::Error {
+            return Err(format!("Found {} repeated ENTRY elements or attributes in balance.xml:\n    {}",
+                               duplicates.len(), duplicates.join("\n    ")));
+        }
+        for duplicate in &duplicates {
+            eprintln!("Warning: {}", duplicate);
+        }
+    }
+
+    Ok(document)
 }
 
-fn calculate_new_balance(unit_objmask_map: &FnvIndexMap<String, FnvHashSet<&'static str>>,
-                         old_unit_balance: &UnitBalance) -> UnitBalance {
+fn describe_repeated_cells(keys: &[String]) -> String {
+    match keys {
+        [] => String::new(),
+        [key] => format!(", repeating {}", key),
+        [first, rest @ ..] => format!(", repeating {} and {} others", first, rest.len()),
+    }
+}
+
+/// Parse a balance cell value, returning the modifier and whether it was
+/// in a lenient form such as " +120% " or "1.2e2". Values that are not
+/// meant as numbers at all give `None`.
+fn parse_modifier(value: &str) -> Result<Option<(f32, bool)>, String> {
+    let lenient = !value.chars().all(|c| c.is_ascii_digit() || c == '-' || c == '.');
+
+    let number = value.trim();
+    let number = number.strip_prefix('+').unwrap_or(number);
+    let number = number.strip_suffix('%').unwrap_or(number).trim_end();
+    if number.is_empty() {
+        return Err(format!("\"{}\" is empty", value));
+    }
+    if !number.starts_with(|c: char| c.is_ascii_digit() || c == '-' || c == '.') {
+        return Ok(None);
+    }
+
+    match number.parse::<f32>() {
+        Ok(modifier) if modifier.is_finite() => Ok(Some((modifier, lenient))),
+        _ => Err(format!("\"{}\" is not a valid number", value)),
+    }
+}
+
+/// Collect the balance cells of `old_unit_balance` that apply when unit
+/// A attacks unit B, through either their names or their objmasks.
+fn collect_factors<'a>(unit_a: &'a str, unit_a_objmask: &'a FnvHashSet<&'static str>,
+                       unit_b: &'a str, unit_b_objmask: &'a FnvHashSet<&'static str>,
+                       old_unit_balance: &UnitBalance, factors: &mut Vec<Factor<'a>>) {
+    factors.clear();
+
+    // Iterate over unit name and object mask names for unit A.
+    let unit_a_names_iter = std::iter::once(unit_a)
+        .chain(unit_a_objmask.iter().cloned());
+    for entry_name in unit_a_names_iter {
+        let entry = match old_unit_balance.entries.get(entry_name) {
+            Some(entry) => entry,
+            None => continue,
+        };
+        // Iterate over unit name and object mask names for unit B.
+        let unit_b_names_iter = std::iter::once(unit_b)
+            .chain(unit_b_objmask.iter().cloned());
+        for attrib_name in unit_b_names_iter {
+            // Missing cells count as 100 and are left out.
+            if let Some(&modifier) = entry.modifiers.get(attrib_name) {
+                factors.push(Factor {
+                    attacker: entry_name,
+                    target: attrib_name,
+                    modifier,
+                    direct: entry_name == unit_a && attrib_name == unit_b,
+                });
+            }
+        }
+    }
+}
+
+fn calculate_new_balance(unit_objmask_map: &UnitObjmaskMap,
+                         old_unit_balance: &UnitBalance,
+                         options: &BalanceOptions) -> UnitBalance {
     let mut new_unit_balance = UnitBalance::default();
 
+    // Only the selected objmask flags are expanded, any others are
+    // flattened alongside the units as if they were units themselves.
+    let mut unit_objmask_map: UnitObjmaskMap = unit_objmask_map.iter()
+        .map(|(unit, objmask)| {
+            let objmask = objmask.iter()
+                .filter(|objmask_name| options.expands_flag(objmask_name))
+                .cloned()
+                .collect();
+            (unit.clone(), objmask)
+        })
+        .collect();
+    for &(_, objmask_name) in OBJMASK_INFO.iter() {
+        if !options.expands_flag(objmask_name) {
+            unit_objmask_map.insert(objmask_name.to_owned(), FnvHashSet::default());
+        }
+    }
+
     // Calculate the matrix of all unit balancing modifiers.
+    let mut factors = Vec::new();
     for (unit_a, unit_a_objmask) in unit_objmask_map.iter() {
         let new_entry = new_unit_balance.entries.entry(unit_a.clone()).or_default();
         for (unit_b, unit_b_objmask) in unit_objmask_map.iter() {
-            let mut balance = 100.0;
-
-            // Iterate over unit name and object mask names for unit A.
-            let unit_a_names_iter = std::iter::once(unit_a.as_str())
-                .chain(unit_a_objmask.iter().cloned());
-            for entry_name in unit_a_names_iter {
-                let entry = old_unit_balance.entries.get(entry_name);
-                // Iterate over unit name and object mask names for
-                // unit B.
-                let unit_b_names_iter = std::iter::once(unit_b.as_str())
-                    .chain(unit_b_objmask.iter().cloned());
-                for attrib_name in unit_b_names_iter {
-                    // Get the modifier if it exists, otherwise assume 100.
-                    let modifier = *entry
-                        .and_then(|entry| entry.modifiers.get(attrib_name))
-                        .unwrap_or(&100.0);
-
-                    balance *= modifier / 100.0;
-                }
-            }
+            collect_factors(unit_a, unit_a_objmask, unit_b, unit_b_objmask, old_unit_balance, &mut factors);
+            let balance = options.combiner.combine(&factors);
 
             new_entry.modifiers.insert(unit_b.clone(), balance);
         }
@@ -527,49 +1663,231 @@ fn calculate_new_balance(unit_objmask_map: &FnvIndexMap<String, FnvHashSet<&'sta
 
     // Reset objmask scaling to 100, not strictly necessary since they
     // are bugged, but might as well do it for correctness sake.
-    for &(_, objmask_name) in OBJMASK_INFO.iter() {
+    for &(_, objmask_name) in OBJMASK_INFO.iter().filter(|(_, name)| options.expands_flag(name)) {
         let mut modifiers = FnvIndexMap::default();
         for (unit, _) in unit_objmask_map.iter() {
             modifiers.insert(unit.to_owned(), 100.0);
         }
-        new_unit_balance.entries.insert(objmask_name.to_owned(), UnitBalanceEntry { modifiers });
+        new_unit_balance.entries.insert(objmask_name.to_owned(), UnitBalanceEntry {
+            modifiers,
+            ..Default::default()
+        });
     }
 
     for (_, entry) in &mut new_unit_balance.entries {
-        for &(_, objmask_name) in OBJMASK_INFO.iter() {
+        for &(_, objmask_name) in OBJMASK_INFO.iter().filter(|(_, name)| options.expands_flag(name)) {
             entry.modifiers.insert(objmask_name.to_owned(), 100.0);
         }
     }
 
+    // Carry over attributes that are not balance cells.
+    for (entry_name, old_entry) in &old_unit_balance.entries {
+        let new_entry = match new_unit_balance.entries.get_mut(entry_name) {
+            Some(entry) => entry,
+            None => continue,
+        };
+        for (key, value) in &old_entry.passthrough {
+            if new_entry.modifiers.contains_key(key) {
+                eprintln!("Warning: {} vs {} value \"{}\" replaced by the flattened modifier", entry_name, key, value);
+            } else {
+                new_entry.passthrough.insert(key.clone(), value.clone());
+            }
+        }
+        new_entry.children = old_entry.children.clone();
+        for (key, lexical) in &old_entry.lexical {
+            if new_entry.modifiers.contains_key(key) {
+                new_entry.lexical.insert(key.clone(), lexical.clone());
+            }
+        }
+    }
+
     new_unit_balance
 }
 
+/// Whether two modifiers are equal, allowing for the rounding error of
+/// multiplying a value by 100 and dividing it by 100 again.
+fn same_modifier(a: f32, b: f32) -> bool {
+    (a - b).abs() <= a.abs().max(b.abs()) * 4.0 * f32::EPSILON
+}
+
+#[cfg(feature = "serde")]
 fn write_new_balance(writer: &mut dyn Write, new_unit_balance: &UnitBalance) -> Result<(), quick_xml::Error> {
-    let mut balance_xml_out = Writer::new_with_indent(writer, b' ', 2);
+    write_balance_xml(writer, &single_table_document(new_unit_balance), None, &OutputFormat::default())
+}
+
+/// As `write_new_balance`, without rounding modifiers.
+fn write_exact_balance(writer: &mut dyn Write, unit_balance: &UnitBalance) -> Result<(), quick_xml::Error> {
+    let format = OutputFormat {
+        exact_values: true,
+        ..OutputFormat::default()
+    };
+    write_balance_xml(writer, &single_table_document(unit_balance), None, &format)
+}
+
+fn single_table_document(unit_balance: &UnitBalance) -> BalanceDocument {
+    BalanceDocument {
+        tables: vec![BalanceTable { unit_balance: unit_balance.clone(), ..BalanceTable::default() }],
+        ..BalanceDocument::default()
+    }
+}
+
+/// Write every table of a balance document, in `encoding`.
+fn write_new_document(writer: &mut dyn Write, document: &BalanceDocument, encoding: &'static Encoding,
+                      format: &OutputFormat) -> Result<(), String> {
+    if encoding == UTF_8 {
+        if document.utf8_bom {
+            writer.write_all(encoding::UTF_8_BOM)
+                .map_err(|e| format!("Failed to write new balance.xml file: {}", e))?;
+        }
+        return write_balance_xml(writer, document, None, format)
+            .map_err(|e| format!("Failed to write new balance.xml file: {}", e));
+    }
+
+    let mut balance_xml = Vec::new();
+    write_balance_xml(&mut balance_xml, document, Some(encoding::declaration_name(encoding)), format)
+        .map_err(|e| format!("Failed to write new balance.xml file: {}", e))?;
+    let balance_xml = String::from_utf8(balance_xml)
+        .map_err(|e| format!("Failed to write new balance.xml file: {}", e))?;
+
+    encoding::write_encoded(writer, &balance_xml, encoding)
+}
+
+/// Write a balance file with a TABLE for each set of TABLE attributes and
+/// the balance to write in it, after a comment holding the `header` lines
+/// if there are any.
+fn write_balance_xml(writer: &mut dyn Write, document: &BalanceDocument, declared_encoding: Option<&str>,
+                     format: &OutputFormat) -> Result<(), quick_xml::Error> {
+    let mut balance_xml_out = Writer::new(writer);
 
     eprintln!("Writing new balance.xml");
 
-    balance_xml_out.write_event(Event::Decl(BytesDecl::new(b"1.0", None, None)))?;
+    let declared_encoding = declared_encoding.map(str::as_bytes);
+    balance_xml_out.write_event(Event::Decl(BytesDecl::new(b"1.0", declared_encoding, None)))?;
 
-    balance_xml_out.write_event(Event::Start(BytesStart::borrowed(b"ROOT", b"ROOT".len())))?;
+    let line_break = |level| Event::Text(BytesText::from_escaped(format.line_break(level)));
+    let kept = |content: &str| Event::Text(BytesText::from_escaped(format.kept_content(content)));
 
-    balance_xml_out.write_event(Event::Start(BytesStart::borrowed(b"TABLE", b"TABLE".len())))?;
+    let header = &document.header;
+    if !header.is_empty() {
+        let comment = if format.compact {
+            format!(" {} ", header.join("; ")).into_bytes()
+        } else {
+            let mut comment = Vec::new();
+            for line in header {
+                comment.extend(format.line_break(1));
+                comment.extend(line.as_bytes());
+            }
+            comment.extend(format.line_break(0));
+            comment
+        };
+        balance_xml_out.write_event(line_break(0))?;
+        balance_xml_out.write_event(Event::Comment(BytesText::from_escaped(comment)))?;
+    }
+
+    for content in &document.prolog {
+        balance_xml_out.write_event(line_break(0))?;
+        balance_xml_out.write_event(kept(content))?;
+    }
+
+    let root_attributes = document.root_attributes.iter().map(|(key, value)| (key.as_str(), value.as_str()));
+    balance_xml_out.write_event(line_break(0))?;
+    balance_xml_out.write_event(Event::Start(element(b"ROOT", root_attributes, b" ")))?;
 
-    for (entry_name, entry) in &new_unit_balance.entries {
-        let mut entry_elem = BytesStart::owned(b"ENTRY".to_vec(), b"ENTRY".len());
+    for (table_index, table) in document.tables.iter().enumerate() {
+        for (_, content) in document.kept.iter().filter(|(before, _)| *before == table_index) {
+            balance_xml_out.write_event(line_break(1))?;
+            balance_xml_out.write_event(kept(content))?;
+        }
 
-        entry_elem.push_attribute(("name", entry_name.as_str()));
-        for (modifier_name, modifier) in &entry.modifiers {
-            let modifier_str = (modifier.round() as i32).to_string();
-            entry_elem.push_attribute((modifier_name.as_str(), modifier_str.as_str()));
+        // Entries of an implicit table are written directly inside ROOT.
+        let level = if table.implicit { 1 } else { 2 };
+        if !table.implicit {
+            let table_attributes = table.attributes.iter().map(|(key, value)| (key.as_str(), value.as_str()));
+            balance_xml_out.write_event(line_break(1))?;
+            balance_xml_out.write_event(Event::Start(element(b"TABLE", table_attributes, b" ")))?;
         }
 
-        balance_xml_out.write_event(Event::Empty(entry_elem))?;
+        let attribute_separator = if format.attribute_per_line {
+            format.line_break(level + 1)
+        } else {
+            b" ".to_vec()
+        };
+
+        let unit_balance = &table.unit_balance;
+        for (entry_name, entry) in &unit_balance.entries {
+            for (_, content) in table.kept.iter().filter(|(before, _)| before.as_ref() == Some(entry_name)) {
+                balance_xml_out.write_event(line_break(level))?;
+                balance_xml_out.write_event(kept(content))?;
+            }
+
+            let modifiers = entry.modifiers.iter().map(|(modifier_name, &modifier)| {
+                // Keep the original form of a value that was not changed, so
+                // "100.0" is not rewritten as "100".
+                let modifier_str = match entry.lexical.get(modifier_name) {
+                    Some((original, lexical)) if same_modifier(*original, modifier) => Cow::Borrowed(lexical.as_str()),
+                    _ if format.exact_values => Cow::Owned(modifier.to_string()),
+                    _ => Cow::Owned((modifier.round() as i32).to_string()),
+                };
+                (modifier_name.as_str(), modifier_str)
+            });
+            let attributes = std::iter::once(("name", Cow::Borrowed(entry_name.as_str())))
+                .chain(modifiers)
+                .chain(entry.passthrough.iter().map(|(key, value)| (key.as_str(), Cow::Borrowed(value.as_str()))));
+            let entry_elem = element(b"ENTRY", attributes, &attribute_separator);
+
+            balance_xml_out.write_event(line_break(level))?;
+            if entry.children.is_empty() {
+                balance_xml_out.write_event(Event::Empty(entry_elem))?;
+            } else {
+                balance_xml_out.write_event(Event::Start(entry_elem))?;
+                balance_xml_out.write_event(kept(&entry.children))?;
+                balance_xml_out.write_event(Event::End(BytesEnd::borrowed(b"ENTRY")))?;
+            }
+        }
+
+        // Content after the last entry, or before an entry that is no
+        // longer in the table.
+        let trailing = table.kept.iter().filter(|(before, _)| {
+            before.as_ref().is_none_or(|before| !unit_balance.entries.contains_key(before))
+        });
+        for (_, content) in trailing {
+            balance_xml_out.write_event(line_break(level))?;
+            balance_xml_out.write_event(kept(content))?;
+        }
+
+        if !table.implicit {
+            balance_xml_out.write_event(line_break(1))?;
+            balance_xml_out.write_event(Event::End(BytesEnd::borrowed(b"TABLE")))?;
+        }
     }
 
-    balance_xml_out.write_event(Event::End(BytesEnd::borrowed(b"TABLE")))?;
+    for (_, content) in document.kept.iter().filter(|(before, _)| *before >= document.tables.len()) {
+        balance_xml_out.write_event(line_break(1))?;
+        balance_xml_out.write_event(kept(content))?;
+    }
 
+    balance_xml_out.write_event(line_break(0))?;
     balance_xml_out.write_event(Event::End(BytesEnd::borrowed(b"ROOT")))?;
+    // The file ends with a line ending, even when written on one line.
+    balance_xml_out.write_event(Event::Text(BytesText::from_escaped(format.line_ending())))?;
 
     Ok(())
+}
+
+/// Build an element with its attributes escaped and each preceded by
+/// `separator`.
+fn element<K, V>(name: &[u8], attributes: impl IntoIterator<Item = (K, V)>,
+                 separator: &[u8]) -> BytesStart<'static>
+    where K: AsRef<str>, V: AsRef<str>
+{
+    let mut content = name.to_vec();
+    for (key, value) in attributes {
+        content.extend_from_slice(separator);
+        content.extend_from_slice(key.as_ref().as_bytes());
+        content.extend_from_slice(b"=\"");
+        content.extend_from_slice(&quick_xml::escape::escape(value.as_ref().as_bytes()));
+        content.push(b'"');
+    }
+
+    BytesStart::owned(content, name.len())
 }
\ No newline at end of file
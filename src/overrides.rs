@@ -0,0 +1,190 @@
+//! Override rules applied to the flattened balance after it has been
+//! calculated.
+//!
+//! Each non-empty line of an overrides file holds one rule, applied in
+//! order, of the form `<attacker> vs <target>: <operation>`, where both
+//! sides are unit selectors (see `selector`) and `#` starts a comment:
+//!
+//! ```text
+//! # Siege vehicles pick up both the SIEGE and VEHICLE factors.
+//! [S & V] vs *: clamp 25 200
+//! Hoplite vs [M]: set 150
+//! * vs [3]: scale 0.5
+//! ```
+//!
+//! The operations are `set <value>`, `scale <factor>`, `min <value>`
+//! (raise anything below the value), `max <value>` (lower anything above
+//! the value) and `clamp <min> <max>`.
+
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use crate::selector::UnitSelector;
+use crate::{encoding, units, UnitBalance, UnitObjmaskMap};
+
+#[derive(Clone, Copy, Debug)]
+enum OverrideOp {
+    Set(f32),
+    Scale(f32),
+    Min(f32),
+    Max(f32),
+    Clamp(f32, f32),
+}
+
+impl OverrideOp {
+    fn apply(self, value: f32) -> f32 {
+        match self {
+            OverrideOp::Set(new_value) => new_value,
+            OverrideOp::Scale(factor) => value * factor,
+            OverrideOp::Min(min) => value.max(min),
+            OverrideOp::Max(max) => value.min(max),
+            OverrideOp::Clamp(min, max) => value.max(min).min(max),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+struct OverrideRule {
+    line: usize,
+    attacker: UnitSelector,
+    target: UnitSelector,
+    op: OverrideOp,
+}
+
+#[derive(Clone, Debug)]
+pub struct BalanceOverrides {
+    path: PathBuf,
+    /// The overrides file as it was read, for the provenance header.
+    bytes: Vec<u8>,
+    rules: Vec<OverrideRule>,
+}
+
+impl BalanceOverrides {
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+}
+
+pub fn parse_overrides(overrides_path: &Path) -> Result<BalanceOverrides, String> {
+    let file_name = format!("overrides file \"{}\"", overrides_path.display());
+    let bytes = encoding::read_file(overrides_path, &file_name)?;
+    let overrides_text = encoding::decode_text(&bytes, &file_name)?;
+
+    eprintln!("Processing overrides {}", overrides_path.display());
+
+    let mut rules = Vec::new();
+    for (i, line) in overrides_text.lines().enumerate() {
+        let line_number = i + 1;
+        let error = |e: String| format!("{} on line {} of \"{}\"", e, line_number, overrides_path.display());
+
+        let rule = line.split('#').next().unwrap().trim();
+        if rule.is_empty() {
+            continue;
+        }
+
+        let (pair, op) = match rule.split_once(':') {
+            Some(parts) => parts,
+            None => return Err(error("Expected \"<attacker> vs <target>: <operation>\"".to_owned())),
+        };
+        let (attacker, target) = match pair.split_once(" vs ") {
+            Some(parts) => parts,
+            None => return Err(error("Expected \"<attacker> vs <target>: <operation>\"".to_owned())),
+        };
+
+        rules.push(OverrideRule {
+            line: line_number,
+            attacker: UnitSelector::parse(attacker).map_err(error)?,
+            target: UnitSelector::parse(target).map_err(error)?,
+            op: parse_op(op).map_err(error)?,
+        });
+    }
+
+    Ok(BalanceOverrides { path: overrides_path.to_owned(), bytes, rules })
+}
+
+fn parse_op(op: &str) -> Result<OverrideOp, String> {
+    let mut words = op.split_whitespace();
+    let name = words.next().ok_or_else(|| "Missing override operation".to_owned())?;
+    let args = words
+        .map(|arg| arg.parse::<f32>().map_err(|e| format!("Failed to parse \"{}\": {}", arg, e)))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    match (name, args.as_slice()) {
+        ("set", &[value]) => Ok(OverrideOp::Set(value)),
+        ("scale", &[factor]) => Ok(OverrideOp::Scale(factor)),
+        ("min", &[min]) => Ok(OverrideOp::Min(min)),
+        ("max", &[max]) => Ok(OverrideOp::Max(max)),
+        ("clamp", &[min, max]) if min <= max => Ok(OverrideOp::Clamp(min, max)),
+        ("clamp", &[_, _]) => Err("The clamp minimum must not exceed its maximum".to_owned()),
+        ("set", _) | ("scale", _) | ("min", _) | ("max", _) | ("clamp", _) => {
+            Err(format!("Wrong number of arguments for \"{}\"", name))
+        }
+        _ => Err(format!("Unknown override operation \"{}\"", name)),
+    }
+}
+
+/// A cell changed by an override rule.
+#[derive(Clone, Debug)]
+pub struct AppliedOverride {
+    pub attacker: String,
+    pub target: String,
+    old_value: f32,
+    new_value: f32,
+    line: usize,
+}
+
+impl fmt::Display for AppliedOverride {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} vs {}: {} -> {} (line {})", self.attacker, self.target, round_for_display(self.old_value),
+               round_for_display(self.new_value), self.line)
+    }
+}
+
+/// Apply the override rules to every matching pair of units, leaving out
+/// meta entries, returning each cell that was changed.
+pub fn apply_overrides(unit_objmask_map: &UnitObjmaskMap, unit_balance: &mut UnitBalance,
+                       overrides: &BalanceOverrides) -> Vec<AppliedOverride> {
+    let mut applied = Vec::new();
+    for rule in &overrides.rules {
+        for unit_a in units(unit_objmask_map) {
+            if !rule.attacker.matches(unit_a, &unit_objmask_map[unit_a]) {
+                continue;
+            }
+
+            let entry = match unit_balance.entries.get_mut(unit_a) {
+                Some(entry) => entry,
+                None => continue,
+            };
+
+            for unit_b in units(unit_objmask_map) {
+                if !rule.target.matches(unit_b, &unit_objmask_map[unit_b]) {
+                    continue;
+                }
+
+                if let Some(value) = entry.modifiers.get_mut(unit_b) {
+                    let new_value = rule.op.apply(*value);
+                    if new_value != *value {
+                        applied.push(AppliedOverride {
+                            attacker: unit_a.clone(),
+                            target: unit_b.clone(),
+                            old_value: *value,
+                            new_value,
+                            line: rule.line,
+                        });
+                        *value = new_value;
+                    }
+                }
+            }
+        }
+    }
+
+    applied
+}
+
+fn round_for_display(value: f32) -> f32 {
+    (value * 100.0).round() / 100.0
+}
@@ -0,0 +1,172 @@
+//! Partial balance patches applied on top of the base balance table
+//! before it is flattened.
+//!
+//! A patch is an XML fragment using the same ENTRY layout as balance.xml.
+//! `ENTRY` (or `SET`) elements set cells, `MULTIPLY` elements scale cells
+//! by the given factor, and `REMOVE` elements remove the named cells, or
+//! the whole entry when no cells are named:
+//!
+//! ```xml
+//! <PATCH>
+//!   <ENTRY name="Flag_M_OBJMASK_MOUNTED" Flag_5_OBJMASK_PIKE="60"/>
+//!   <MULTIPLY name="Hoplite" Cavalry="1.5"/>
+//!   <REMOVE name="Archer" Flag_H_OBJMASK_HEAVY_INF=""/>
+//! </PATCH>
+//! ```
+
+use std::path::{Path, PathBuf};
+
+use quick_xml::events::Event;
+use quick_xml::Reader;
+
+use crate::{encoding, FnvIndexMap, UnitBalance};
+
+#[derive(Clone, Debug)]
+enum PatchOp {
+    Set { entry: String, modifier: String, value: f32 },
+    Multiply { entry: String, modifier: String, factor: f32 },
+    Remove { entry: String, modifier: Option<String> },
+}
+
+#[derive(Clone, Debug)]
+pub struct BalancePatch {
+    path: PathBuf,
+    /// The patch file as it was read, for the provenance header.
+    bytes: Vec<u8>,
+    ops: Vec<PatchOp>,
+}
+
+impl BalancePatch {
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+}
+
+pub fn parse_patch(patch_path: &Path) -> Result<BalancePatch, String> {
+    let file_name = format!("patch \"{}\"", patch_path.display());
+    let bytes = encoding::read_file(patch_path, &file_name)?;
+    let (patch_xml, _) = encoding::decode_xml(&bytes, &file_name)?;
+    let mut patch_document = Reader::from_str(&patch_xml);
+
+    eprintln!("Processing patch {}", patch_path.display());
+
+    let error = |e: String| format!("{} in patch \"{}\"", e, patch_path.display());
+
+    let mut ops = Vec::new();
+    let mut buf = Vec::new();
+    loop {
+        let event = patch_document.read_event(&mut buf)
+            .map_err(|e| error(format!("Failed to read patch: {}", e)))?;
+        match event {
+            Event::Start(e) | Event::Empty(e) => {
+                let element = e.name().to_owned();
+                if !matches!(element.as_slice(), b"ENTRY" | b"SET" | b"MULTIPLY" | b"REMOVE") {
+                    buf.clear();
+                    continue;
+                }
+
+                let mut name = String::new();
+                let mut cells = Vec::new();
+                for attrib in e.attributes() {
+                    let attrib = attrib
+                        .map_err(|e| error(format!("Failed to get attribute in a patch element: {}", e)))?;
+                    let value = attrib.unescape_and_decode_value(&patch_document)
+                        .map_err(|e| error(format!("Failed to get attribute value in a patch element: {}", e)))?;
+                    if attrib.key == b"name" {
+                        name = value;
+                    } else {
+                        let key = patch_document.decode(attrib.key)
+                            .map_err(|e| error(format!("Failed to get attribute key in a patch element: {}", e)))?
+                            .to_owned();
+                        cells.push((key, value));
+                    }
+                }
+
+                if name.is_empty() {
+                    return Err(error("No \"name\" attribute found in a patch element".to_owned()));
+                }
+
+                if element == b"REMOVE" && cells.is_empty() {
+                    ops.push(PatchOp::Remove { entry: name.clone(), modifier: None });
+                }
+
+                for (modifier, value) in cells {
+                    let entry = name.clone();
+                    let parse_value = || value.trim().parse::<f32>()
+                        .map_err(|e| error(format!("Failed to parse value \"{}\" for {} vs {}: {}", value, entry, modifier, e)));
+                    let op = match element.as_slice() {
+                        b"MULTIPLY" => PatchOp::Multiply { factor: parse_value()?, entry, modifier },
+                        b"REMOVE" => PatchOp::Remove { entry, modifier: Some(modifier) },
+                        _ => PatchOp::Set { value: parse_value()?, entry, modifier },
+                    };
+                    ops.push(op);
+                }
+            }
+            Event::Eof => break,
+            _ => (),
+        }
+
+        buf.clear();
+    }
+
+    Ok(BalancePatch { path: patch_path.to_owned(), bytes, ops })
+}
+
+/// Apply `patches` in order, returning a description of every cell that
+/// more than one patch touched.
+pub fn apply_patches(unit_balance: &mut UnitBalance, patches: &[BalancePatch]) -> Vec<String> {
+    let mut touched: FnvIndexMap<(String, String), Vec<usize>> = FnvIndexMap::default();
+    let mut touch = |entry: &str, modifier: &str, layer: usize| {
+        let layers = touched.entry((entry.to_owned(), modifier.to_owned())).or_default();
+        if layers.last() != Some(&layer) {
+            layers.push(layer);
+        }
+    };
+
+    for (layer, patch) in patches.iter().enumerate() {
+        for op in &patch.ops {
+            match op {
+                PatchOp::Set { entry, modifier, value } => {
+                    unit_balance.entries.entry(entry.clone()).or_default()
+                        .modifiers.insert(modifier.clone(), *value);
+                    touch(entry, modifier, layer);
+                }
+                PatchOp::Multiply { entry, modifier, factor } => {
+                    // A missing cell counts as 100, as it does when
+                    // calculating the new balance.
+                    let value = unit_balance.entries.entry(entry.clone()).or_default()
+                        .modifiers.entry(modifier.clone()).or_insert(100.0);
+                    *value *= factor;
+                    touch(entry, modifier, layer);
+                }
+                PatchOp::Remove { entry, modifier: Some(modifier) } => {
+                    if let Some(balance_entry) = unit_balance.entries.get_mut(entry) {
+                        balance_entry.modifiers.shift_remove(modifier);
+                    }
+                    touch(entry, modifier, layer);
+                }
+                PatchOp::Remove { entry, modifier: None } => {
+                    if let Some(balance_entry) = unit_balance.entries.shift_remove(entry) {
+                        for modifier in balance_entry.modifiers.keys() {
+                            touch(entry, modifier, layer);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    touched.iter()
+        .filter(|(_, layers)| layers.len() > 1)
+        .map(|((entry, modifier), layers)| {
+            let paths: Vec<String> = layers.iter()
+                .map(|&layer| patches[layer].path.display().to_string())
+                .collect();
+            format!("{} vs {} is changed by multiple patches: {}", entry, modifier, paths.join(", "))
+        })
+        .collect()
+}
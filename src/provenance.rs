@@ -0,0 +1,264 @@
+//! Provenance header written at the top of a flattened balance file, and
+//! the `verify` command that checks a file against it.
+//!
+//! The header is an XML comment recording the inputs and options the file
+//! was generated from:
+//!
+//! ```text
+//! <!--
+//!   Generated by ron-objmask-workaround 1.2.1
+//!   balance.xml SHA-256: 2c26b46b68ffc68ff99b453c1d30413413422d706483bfa0f98a5e886266e7ae
+//!   unitrules.xml SHA-256: fcde2b2edba56bf408601fb721fe9b5c338d10ee429ea04fae5511b68fbf8fb9
+//!   patch mod%20a.xml SHA-256: 4e07408562bedb8b60ce05c1decfe3ad16b72230967de01f640b7e4729b49fce
+//!   Options: table=land combine=add patch=mod%20a.xml attribute-per-line
+//!   Units: 57
+//!   Expanded flags: all
+//! -->
+//! ```
+//!
+//! Patch, overrides and base files are recorded with their SHA-256 as well.
+//! XML comments cannot contain `--`, so options are recorded without their
+//! leading dashes, with their value after `=`. Whitespace, `%`, `;` and
+//! repeated dashes are percent-encoded, in options and file names alike.
+
+use std::path::{Path, PathBuf};
+
+use sha2::{Digest, Sha256};
+
+use crate::overrides::BalanceOverrides;
+use crate::patch::BalancePatch;
+use crate::{
+    encoding, flatten_document, parse_balance_xml, parse_options, units, write_new_document, MergePolicy,
+    Options, UnitObjmaskMap, OBJMASK_INFO,
+};
+
+const GENERATED_BY: &str = concat!("Generated by ", env!("CARGO_PKG_NAME"), " ");
+const SHA256: &str = " SHA-256: ";
+const OPTIONS: &str = "Options: ";
+
+/// The header lines for a balance file flattened from `balance_bytes` and
+/// `unit_rules_bytes` with `patches`, `overrides`, the base file read as
+/// `base_bytes` and `options`.
+pub fn header(balance_bytes: &[u8], unit_rules_bytes: &[u8], patches: &[BalancePatch],
+              overrides: &[BalanceOverrides], base_bytes: Option<&[u8]>, unit_objmask_map: &UnitObjmaskMap,
+              options: &Options) -> Vec<String> {
+    let expanded_flags: Vec<String> = OBJMASK_INFO.iter()
+        .filter(|(_, objmask_name)| options.balance.expands_flag(objmask_name))
+        .map(|(c, _)| c.to_string())
+        .collect();
+    let expanded_flags = match expanded_flags.len() {
+        0 => "none".to_owned(),
+        n if n == OBJMASK_INFO.len() => "all".to_owned(),
+        _ => expanded_flags.join(" "),
+    };
+
+    let mut lines = vec![
+        format!("{}{}", GENERATED_BY, env!("CARGO_PKG_VERSION")),
+        format!("balance.xml{}{}", SHA256, sha256_hex(balance_bytes)),
+        format!("unitrules.xml{}{}", SHA256, sha256_hex(unit_rules_bytes)),
+    ];
+
+    let files = patches.iter().map(|patch| ("patch", patch.path(), patch.bytes()))
+        .chain(overrides.iter().map(|overrides| ("overrides", overrides.path(), overrides.bytes())))
+        .chain(options.base.as_deref().zip(base_bytes).map(|(path, bytes)| ("base", path, bytes)));
+    for (kind, path, bytes) in files {
+        lines.push(format!("{} {}{}{}", kind, escape(&path.display().to_string()), SHA256, sha256_hex(bytes)));
+    }
+
+    let arguments = encode_arguments(&options.arguments);
+    lines.extend(vec![
+        format!("{}{}", OPTIONS, if arguments.is_empty() { "none" } else { &arguments }),
+        format!("Units: {}", units(unit_objmask_map).count()),
+        format!("Expanded flags: {}", expanded_flags),
+    ]);
+
+    lines
+}
+
+/// Split a comment into header lines, if it is a provenance header. The
+/// lines of a compact header are separated by `; `.
+pub fn parse_header(comment: &str) -> Option<Vec<String>> {
+    let lines: Vec<String> = comment.lines()
+        .flat_map(|line| line.split("; "))
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .map(str::to_owned)
+        .collect();
+
+    if lines.first()?.starts_with(GENERATED_BY) {
+        Some(lines)
+    } else {
+        None
+    }
+}
+
+/// The command line arguments recorded in header `lines`, other than the
+/// input files.
+pub fn recorded_arguments(lines: &[String]) -> Result<Vec<String>, String> {
+    decode_arguments(field(lines, OPTIONS)?)
+}
+
+pub fn run_verify(args: &[String]) -> Result<(), String> {
+    let usage = "Usage: verify [--unitrules <file>] <generated balance file> <balance file>";
+
+    let mut unit_rules_path = None;
+    let mut paths = Vec::new();
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or_else(|| format!("Missing value for option \"{}\"", arg));
+        match arg.as_str() {
+            "--unitrules" => unit_rules_path = Some(PathBuf::from(value()?)),
+            _ if arg.starts_with('-') && arg != "-" => return Err(format!("Unknown option \"{}\"", arg)),
+            _ => paths.push(arg.clone()),
+        }
+    }
+
+    let (generated_path, balance_xml_path) = match paths.as_slice() {
+        [generated, balance] => (Path::new(generated), balance),
+        _ => return Err(usage.to_owned()),
+    };
+
+    let generated_bytes = encoding::read_file(generated_path, "balance.xml")?;
+    let (generated_xml, _) = encoding::decode_xml(&generated_bytes, "balance.xml")?;
+    let recorded = parse_balance_xml(&generated_xml, MergePolicy::default())?.header;
+    if recorded.is_empty() {
+        return Err(format!("{} has no provenance header to verify it against. Only the main run and update write \
+                            one, not compile or factorize", generated_path.display()));
+    }
+
+    let version = field(&recorded, GENERATED_BY)?;
+    if version != env!("CARGO_PKG_VERSION") {
+        eprintln!("Warning: {} was generated by version {}, not {}", generated_path.display(), version,
+                  env!("CARGO_PKG_VERSION"));
+    }
+
+    let mut arguments = recorded_arguments(&recorded)?;
+    if let Some(unit_rules_path) = unit_rules_path {
+        arguments.push("--unitrules".to_owned());
+        arguments.push(unit_rules_path.display().to_string());
+    }
+    arguments.push(balance_xml_path.clone());
+
+    let (options, _) = parse_options(&arguments)
+        .map_err(|e| format!("Failed to use the options in the provenance header: {}", e))?;
+
+    eprintln!("Regenerating {}", generated_path.display());
+
+    let (document, input_encoding) = flatten_document(Path::new(balance_xml_path), &options, false)?;
+
+    let regenerated_inputs = input_hashes(&document.header);
+    for (input, sha256) in input_hashes(&recorded) {
+        if !regenerated_inputs.contains(&(input, sha256)) {
+            return Err(format!("The {} is not the one that {} was generated from", unescape(input)?,
+                               generated_path.display()));
+        }
+    }
+
+    let mut regenerated = Vec::new();
+    write_new_document(&mut regenerated, &document, options.output_encoding.unwrap_or(input_encoding),
+                       &options.format)?;
+
+    if regenerated != generated_bytes {
+        let first_difference = regenerated.iter().zip(&generated_bytes)
+            .position(|(a, b)| a != b)
+            .unwrap_or_else(|| regenerated.len().min(generated_bytes.len()));
+        let line = generated_bytes[..first_difference].iter().filter(|&&b| b == b'\n').count() + 1;
+        return Err(format!("{} differs from the output regenerated from its recorded inputs, from line {}",
+                           generated_path.display(), line));
+    }
+
+    eprintln!("{} matches the output regenerated from its recorded inputs", generated_path.display());
+
+    Ok(())
+}
+
+/// The rest of the header line starting with `prefix`.
+fn field<'a>(lines: &'a [String], prefix: &str) -> Result<&'a str, String> {
+    lines.iter()
+        .find_map(|line| line.strip_prefix(prefix))
+        .ok_or_else(|| format!("The provenance header has no \"{}\" line", prefix.trim_end_matches([':', ' '])))
+}
+
+/// The input files recorded in header `lines`, with their SHA-256.
+fn input_hashes(lines: &[String]) -> Vec<(&str, &str)> {
+    lines.iter().filter_map(|line| line.split_once(SHA256)).collect()
+}
+
+fn sha256_hex(bytes: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(bytes))
+}
+
+/// Record command line arguments as `name=value` for each option followed
+/// by a value, or `name` for a flag.
+fn encode_arguments(arguments: &[String]) -> String {
+    let mut encoded = Vec::new();
+    let mut arguments = arguments.iter().peekable();
+    while let Some(argument) = arguments.next() {
+        let name = argument.strip_prefix("--").unwrap_or(argument);
+        match arguments.next_if(|value| !value.starts_with("--")) {
+            Some(value) => encoded.push(format!("{}={}", escape(name), escape(value))),
+            None => encoded.push(escape(name)),
+        }
+    }
+
+    encoded.join(" ")
+}
+
+fn decode_arguments(encoded: &str) -> Result<Vec<String>, String> {
+    if encoded == "none" {
+        return Ok(Vec::new());
+    }
+
+    let mut arguments = Vec::new();
+    for option in encoded.split_whitespace() {
+        let (name, value) = match option.split_once('=') {
+            Some((name, value)) => (name, Some(value)),
+            None => (option, None),
+        };
+        arguments.push(format!("--{}", unescape(name)?));
+        if let Some(value) = value {
+            arguments.push(unescape(value)?);
+        }
+    }
+
+    Ok(arguments)
+}
+
+fn escape(text: &str) -> String {
+    let mut escaped = String::new();
+    let mut previous = None;
+    for c in text.chars() {
+        if c.is_whitespace() || c == '%' || c == ';' || c == '=' || (c == '-' && previous == Some('-')) {
+            let mut utf8 = [0; 4];
+            for byte in c.encode_utf8(&mut utf8).bytes() {
+                escaped.push_str(&format!("%{:02X}", byte));
+            }
+        } else {
+            escaped.push(c);
+        }
+        previous = Some(c);
+    }
+
+    escaped
+}
+
+fn unescape(text: &str) -> Result<String, String> {
+    let mut bytes = Vec::new();
+    let mut rest = text.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        if byte == b'%' {
+            let hex = tail.get(..2)
+                .and_then(|hex| std::str::from_utf8(hex).ok())
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+                .ok_or_else(|| format!("Invalid escape in recorded option \"{}\"", text))?;
+            bytes.push(hex);
+            rest = &tail[2..];
+        } else {
+            bytes.push(byte);
+            rest = tail;
+        }
+    }
+
+    String::from_utf8(bytes).map_err(|e| format!("Invalid recorded option \"{}\": {}", text, e))
+}
@@ -0,0 +1,136 @@
+//! Arguments and plain text tables shared by the analysis reports.
+
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use crate::combine;
+use crate::{
+    calculate_new_balance, parse_balance, parse_unitrules, sibling_unit_rules_path, BalanceOptions, UnitBalance,
+    UnitObjmaskMap,
+};
+
+/// The value of an option, or an error if it has none.
+pub type OptionValue<'a, 'b> = &'b mut dyn FnMut() -> Result<&'a String, String>;
+
+/// Options shared by the analysis reports, with their positional
+/// arguments.
+pub struct ReportArgs<'a> {
+    unit_rules: Option<PathBuf>,
+    pub balance: BalanceOptions,
+    /// Number of rows to list, from `--top`.
+    pub top: usize,
+    pub positional: Vec<&'a str>,
+}
+
+impl<'a> ReportArgs<'a> {
+    /// Parse `args`, taking `--unitrules` and whichever of `--combine` and
+    /// `--top` are in `shared`. Every other option is passed to `option`
+    /// with its value, which returns whether it was one of the command's
+    /// own options.
+    pub fn parse(args: &'a [String], shared: &[&str],
+                 mut option: impl FnMut(&str, OptionValue<'a, '_>) -> Result<bool, String>)
+                 -> Result<ReportArgs<'a>, String> {
+        let mut report_args = ReportArgs {
+            unit_rules: None,
+            balance: BalanceOptions::default(),
+            top: 5,
+            positional: Vec::new(),
+        };
+
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            let mut value = || args.next().ok_or_else(|| format!("Missing value for option \"{}\"", arg));
+            match arg.as_str() {
+                "--unitrules" => report_args.unit_rules = Some(PathBuf::from(value()?)),
+                "--combine" if shared.contains(&"--combine") => {
+                    let name = value()?;
+                    report_args.balance.combiner = combine::combiner_by_name(name)
+                        .ok_or_else(|| format!("Unknown combine mode \"{}\"", name))?;
+                }
+                "--top" if shared.contains(&"--top") => {
+                    let count = value()?;
+                    report_args.top = count.parse()
+                        .map_err(|e| format!("Failed to parse count \"{}\": {}", count, e))?;
+                }
+                _ if arg.starts_with('-') && arg != "-" => {
+                    if !option(arg, &mut value)? {
+                        return Err(format!("Unknown option \"{}\"", arg));
+                    }
+                }
+                _ => report_args.positional.push(arg.as_str()),
+            }
+        }
+
+        Ok(report_args)
+    }
+
+    /// Parse unitrules.xml, from `--unitrules` or next to the balance file,
+    /// and the first table of the balance file.
+    pub fn read_inputs(&self, balance_xml_path: &Path) -> Result<(UnitObjmaskMap, UnitBalance), String> {
+        let unit_rules_path = match &self.unit_rules {
+            Some(path) => path.clone(),
+            None => sibling_unit_rules_path(balance_xml_path)?,
+        };
+
+        let unit_objmask_map = parse_unitrules(&unit_rules_path)?;
+        let unit_balance = parse_balance(balance_xml_path)?;
+        Ok((unit_objmask_map, unit_balance))
+    }
+
+    /// As `read_inputs`, also returning the flattened balance table.
+    pub fn read_flattened(&self,
+                          balance_xml_path: &Path) -> Result<(UnitObjmaskMap, UnitBalance, UnitBalance), String> {
+        let (unit_objmask_map, old_unit_balance) = self.read_inputs(balance_xml_path)?;
+        let new_unit_balance = calculate_new_balance(&unit_objmask_map, &old_unit_balance, &self.balance);
+        Ok((unit_objmask_map, old_unit_balance, new_unit_balance))
+    }
+}
+
+pub struct Table {
+    header: Vec<String>,
+    rows: Vec<Vec<String>>,
+}
+
+impl Table {
+    pub fn new(header: &[&str]) -> Table {
+        Table {
+            header: header.iter().map(|column| column.to_string()).collect(),
+            rows: Vec::new(),
+        }
+    }
+
+    pub fn add_row(&mut self, row: Vec<String>) {
+        debug_assert_eq!(row.len(), self.header.len());
+        self.rows.push(row);
+    }
+
+    /// Write the table with each column padded to its widest value.
+    pub fn write(&self, writer: &mut dyn Write) -> io::Result<()> {
+        let mut widths: Vec<usize> = self.header.iter().map(|column| column.chars().count()).collect();
+        for row in &self.rows {
+            for (width, value) in widths.iter_mut().zip(row) {
+                *width = (*width).max(value.chars().count());
+            }
+        }
+
+        for row in std::iter::once(&self.header).chain(&self.rows) {
+            let mut line = String::new();
+            for (i, (value, width)) in row.iter().zip(&widths).enumerate() {
+                if i + 1 == row.len() {
+                    line.push_str(value);
+                } else {
+                    line.push_str(&format!("{:<width$}  ", value, width = width));
+                }
+            }
+            writeln!(writer, "{}", line.trim_end())?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Format a modifier to at most two decimal places.
+pub fn format_modifier(modifier: f32) -> String {
+    let rounded = (modifier * 100.0).round() / 100.0;
+    format!("{}", rounded)
+}
@@ -0,0 +1,132 @@
+//! Selection of units by name or by objmask expression.
+//!
+//! A selector is `*` for every unit, a unit name, or an objmask expression
+//! in square brackets. Expressions combine flags, given either by their
+//! full attribute name or their single character code, with `&`, `|`, `!`
+//! and parentheses, e.g. `[S & V]` or `[Flag_M_OBJMASK_MOUNTED | !F]`.
+
+use fnv::FnvHashSet;
+
+use crate::objmask_name_to_attrib_str;
+
+#[derive(Clone, Debug)]
+pub enum UnitSelector {
+    Any,
+    Unit(String),
+    Mask(MaskExpr),
+}
+
+#[derive(Clone, Debug)]
+pub enum MaskExpr {
+    Flag(&'static str),
+    Not(Box<MaskExpr>),
+    And(Box<MaskExpr>, Box<MaskExpr>),
+    Or(Box<MaskExpr>, Box<MaskExpr>),
+}
+
+impl UnitSelector {
+    pub fn parse(selector: &str) -> Result<UnitSelector, String> {
+        let selector = selector.trim();
+        if selector == "*" {
+            Ok(UnitSelector::Any)
+        } else if let Some(expr) = selector.strip_prefix('[').and_then(|s| s.strip_suffix(']')) {
+            MaskExpr::parse(expr).map(UnitSelector::Mask)
+        } else if selector.is_empty() {
+            Err("Empty unit selector".to_owned())
+        } else {
+            Ok(UnitSelector::Unit(selector.to_owned()))
+        }
+    }
+
+    pub fn matches(&self, unit: &str, objmask: &FnvHashSet<&'static str>) -> bool {
+        match self {
+            UnitSelector::Any => true,
+            UnitSelector::Unit(name) => name == unit,
+            UnitSelector::Mask(expr) => expr.matches(objmask),
+        }
+    }
+}
+
+impl MaskExpr {
+    pub fn parse(expr: &str) -> Result<MaskExpr, String> {
+        let tokens = tokenize(expr)?;
+        let mut pos = 0;
+        let result = parse_or(&tokens, &mut pos)
+            .map_err(|e| format!("{} in objmask expression \"{}\"", e, expr))?;
+        if pos != tokens.len() {
+            return Err(format!("Unexpected \"{}\" in objmask expression \"{}\"", tokens[pos], expr));
+        }
+
+        Ok(result)
+    }
+
+    pub fn matches(&self, objmask: &FnvHashSet<&'static str>) -> bool {
+        match self {
+            MaskExpr::Flag(flag) => objmask.contains(flag),
+            MaskExpr::Not(expr) => !expr.matches(objmask),
+            MaskExpr::And(lhs, rhs) => lhs.matches(objmask) && rhs.matches(objmask),
+            MaskExpr::Or(lhs, rhs) => lhs.matches(objmask) || rhs.matches(objmask),
+        }
+    }
+}
+
+fn tokenize(expr: &str) -> Result<Vec<&str>, String> {
+    let mut tokens = Vec::new();
+    let mut rest = expr.trim_start();
+    while let Some(c) = rest.chars().next() {
+        let len = if "&|!()".contains(c) {
+            1
+        } else if c.is_ascii_alphanumeric() || c == '_' {
+            rest.find(|c: char| !(c.is_ascii_alphanumeric() || c == '_')).unwrap_or(rest.len())
+        } else {
+            return Err(format!("Unexpected character '{}' in objmask expression \"{}\"", c, expr));
+        };
+
+        tokens.push(&rest[..len]);
+        rest = rest[len..].trim_start();
+    }
+
+    Ok(tokens)
+}
+
+fn parse_or(tokens: &[&str], pos: &mut usize) -> Result<MaskExpr, String> {
+    let mut lhs = parse_and(tokens, pos)?;
+    while tokens.get(*pos) == Some(&"|") {
+        *pos += 1;
+        let rhs = parse_and(tokens, pos)?;
+        lhs = MaskExpr::Or(Box::new(lhs), Box::new(rhs));
+    }
+
+    Ok(lhs)
+}
+
+fn parse_and(tokens: &[&str], pos: &mut usize) -> Result<MaskExpr, String> {
+    let mut lhs = parse_unary(tokens, pos)?;
+    while tokens.get(*pos) == Some(&"&") {
+        *pos += 1;
+        let rhs = parse_unary(tokens, pos)?;
+        lhs = MaskExpr::And(Box::new(lhs), Box::new(rhs));
+    }
+
+    Ok(lhs)
+}
+
+fn parse_unary(tokens: &[&str], pos: &mut usize) -> Result<MaskExpr, String> {
+    let token = *tokens.get(*pos).ok_or_else(|| "Unexpected end".to_owned())?;
+    *pos += 1;
+    match token {
+        "!" => Ok(MaskExpr::Not(Box::new(parse_unary(tokens, pos)?))),
+        "(" => {
+            let expr = parse_or(tokens, pos)?;
+            if tokens.get(*pos) != Some(&")") {
+                return Err("Missing \")\"".to_owned());
+            }
+            *pos += 1;
+            Ok(expr)
+        }
+        "&" | "|" | ")" => Err(format!("Unexpected \"{}\"", token)),
+        _ => objmask_name_to_attrib_str(token)
+            .map(MaskExpr::Flag)
+            .ok_or_else(|| format!("Unknown OBJ_MASK flag \"{}\"", token)),
+    }
+}
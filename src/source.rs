@@ -0,0 +1,170 @@
+//! TOML authoring format for balance tables.
+//!
+//! A source file holds objmask-level rules under `[flags]` and unit-level
+//! overrides under `[units]`, each keyed by `"<attacker> vs <target>"`:
+//!
+//! ```toml
+//! # Pikes hold off cavalry.
+//! [flags]
+//! "Flag_M_OBJMASK_MOUNTED vs Flag_5_OBJMASK_PIKE" = 50
+//! "5 vs M" = 150
+//!
+//! [units]
+//! "Hoplite vs Flag_M_OBJMASK_MOUNTED" = 120
+//! ```
+//!
+//! Flags in either section may be given by their single character code.
+//! Any cell that is not listed is 100, as with a missing balance.xml cell.
+
+use std::fmt::Write as _;
+use std::io::Write;
+use std::path::Path;
+
+use serde::Deserialize;
+
+use crate::{
+    calculate_new_balance, encoding, objmask_name_to_attrib_str, parse_balance, parse_unitrules,
+    sibling_unit_rules_path, write_new_balance, BalanceOptions, FnvIndexMap, UnitBalance,
+};
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct BalanceSource {
+    #[serde(default)]
+    flags: FnvIndexMap<String, f32>,
+    #[serde(default)]
+    units: FnvIndexMap<String, f32>,
+}
+
+pub fn run_compile(args: &[String]) -> Result<(), String> {
+    let (source_path, unit_rules_path) = match args {
+        [source] => (Path::new(source), sibling_unit_rules_path(Path::new(source))?),
+        [source, unitrules] => (Path::new(source), Path::new(unitrules).to_owned()),
+        _ => return Err("Usage: compile <source file> [unitrules file]".to_owned()),
+    };
+
+    let unit_objmask_map = parse_unitrules(&unit_rules_path)?;
+    let source_unit_balance = parse_source(source_path)?;
+
+    for (entry_name, entry) in &source_unit_balance.entries {
+        let names = std::iter::once(entry_name).chain(entry.modifiers.keys());
+        for name in names {
+            if !is_flag(name) && !unit_objmask_map.contains_key(name) {
+                eprintln!("Warning: unit \"{}\" in the source file was not found in unitrules.xml", name);
+            }
+        }
+    }
+
+    let new_unit_balance = calculate_new_balance(&unit_objmask_map, &source_unit_balance,
+                                                 &BalanceOptions::default());
+
+    write_new_balance(&mut std::io::stdout(), &new_unit_balance)
+        .map_err(|e| format!("Failed to write new balance.xml file: {}", e))
+}
+
+pub fn run_decompile(args: &[String]) -> Result<(), String> {
+    let balance_xml_path = match args {
+        [balance] => Path::new(balance),
+        _ => return Err("Usage: decompile <balance file>".to_owned()),
+    };
+
+    let unit_balance = parse_balance(balance_xml_path)?;
+
+    eprintln!("Writing balance source");
+
+    let source = write_source(&unit_balance);
+    std::io::stdout().write_all(source.as_bytes())
+        .map_err(|e| format!("Failed to write balance source: {}", e))
+}
+
+/// Parse a source file into the sparse balance table it describes.
+fn parse_source(source_path: &Path) -> Result<UnitBalance, String> {
+    let contents = String::from_utf8(encoding::read_file(source_path, "balance source")?)
+        .map_err(|e| format!("Failed to read balance source: {}", e))?;
+
+    eprintln!("Processing balance source");
+
+    let source: BalanceSource = toml::from_str(&contents)
+        .map_err(|e| format!("Failed to parse balance source: {}", e))?;
+
+    let mut unit_balance = UnitBalance::default();
+    for (key, &value) in &source.flags {
+        let (attacker, target) = split_rule(key)?;
+        let attacker = objmask_name_to_attrib_str(attacker)
+            .ok_or_else(|| format!("Unknown OBJ_MASK flag \"{}\" in [flags] rule \"{}\"", attacker, key))?;
+        let target = objmask_name_to_attrib_str(target)
+            .ok_or_else(|| format!("Unknown OBJ_MASK flag \"{}\" in [flags] rule \"{}\"", target, key))?;
+        insert_rule(&mut unit_balance, key, attacker, target, value)?;
+    }
+
+    for (key, &value) in &source.units {
+        let (attacker, target) = split_rule(key)?;
+        let attacker = objmask_name_to_attrib_str(attacker).unwrap_or(attacker);
+        let target = objmask_name_to_attrib_str(target).unwrap_or(target);
+        if is_flag(attacker) && is_flag(target) {
+            eprintln!("Warning: rule \"{}\" in [units] only names OBJ_MASK flags", key);
+        }
+        insert_rule(&mut unit_balance, key, attacker, target, value)?;
+    }
+
+    Ok(unit_balance)
+}
+
+/// Render a balance table as a source file, leaving out neutral cells.
+fn write_source(unit_balance: &UnitBalance) -> String {
+    let mut flags = String::new();
+    let mut units = String::new();
+    for (entry_name, entry) in &unit_balance.entries {
+        for (modifier_name, &modifier) in &entry.modifiers {
+            if modifier == 100.0 {
+                continue;
+            }
+
+            let section = if is_flag(entry_name) && is_flag(modifier_name) {
+                &mut flags
+            } else {
+                &mut units
+            };
+
+            let key = toml::Value::String(format!("{} vs {}", entry_name, modifier_name));
+            if modifier.fract() == 0.0 {
+                writeln!(section, "{} = {}", key, modifier as i64).unwrap();
+            } else {
+                writeln!(section, "{} = {}", key, modifier).unwrap();
+            }
+        }
+    }
+
+    let mut source = String::new();
+    source.push_str("# Balance source, compile with `ron-objmask-workaround compile`.\n");
+    source.push_str("# Cells that are not listed default to 100.\n");
+    source.push_str("\n[flags]\n");
+    source.push_str(&flags);
+    source.push_str("\n[units]\n");
+    source.push_str(&units);
+    source
+}
+
+fn split_rule(key: &str) -> Result<(&str, &str), String> {
+    let mut parts = key.splitn(2, " vs ");
+    match (parts.next(), parts.next()) {
+        (Some(attacker), Some(target)) if !attacker.trim().is_empty() && !target.trim().is_empty() => {
+            Ok((attacker.trim(), target.trim()))
+        }
+        _ => Err(format!("Rule \"{}\" is not of the form \"<attacker> vs <target>\"", key)),
+    }
+}
+
+fn insert_rule(unit_balance: &mut UnitBalance, key: &str, attacker: &str, target: &str,
+               value: f32) -> Result<(), String> {
+    let modifiers = &mut unit_balance.entries.entry(attacker.to_owned()).or_default().modifiers;
+    if modifiers.insert(target.to_owned(), value).is_some() {
+        return Err(format!("Rule \"{}\" sets a cell that was already set", key));
+    }
+
+    Ok(())
+}
+
+fn is_flag(name: &str) -> bool {
+    name.starts_with("Flag_") && objmask_name_to_attrib_str(name).is_some()
+}
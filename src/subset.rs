@@ -0,0 +1,83 @@
+//! Recalculation of only the rows and columns of selected units, with
+//! every other cell carried over from an existing balance table.
+
+use fnv::FnvHashSet;
+
+use crate::{UnitBalance, UnitObjmaskMap};
+
+/// Select the units whose name matches one of the glob `patterns` (where
+/// `*` matches any run of characters and `?` any single character) or
+/// whose objmask contains one of `flags`.
+pub fn select_units(unit_objmask_map: &UnitObjmaskMap, patterns: &[String],
+                    flags: &[&'static str]) -> FnvHashSet<String> {
+    unit_objmask_map.iter()
+        .filter(|(unit, objmask)| {
+            patterns.iter().any(|pattern| glob_matches(pattern, unit))
+                || flags.iter().any(|flag| objmask.contains(flag))
+        })
+        .map(|(unit, _)| unit.clone())
+        .collect()
+}
+
+/// Take the rows and columns of `selected` units from `new_unit_balance`,
+/// carrying every other cell over unchanged from `base_unit_balance`.
+///
+/// `new_unit_balance` must be a full recalculation, which the carried
+/// cells are checked against. A description of every carried cell that
+/// differs is returned.
+pub fn merge_selected_units(new_unit_balance: &UnitBalance, base_unit_balance: &UnitBalance,
+                            selected: &FnvHashSet<String>) -> (UnitBalance, Vec<String>) {
+    let mut merged_unit_balance = base_unit_balance.clone();
+    let mut inconsistencies = Vec::new();
+
+    for (entry_name, new_entry) in &new_unit_balance.entries {
+        let row_selected = selected.contains(entry_name);
+        let merged_entry = merged_unit_balance.entries.entry(entry_name.clone()).or_default();
+        for (modifier_name, &new_modifier) in &new_entry.modifiers {
+            if row_selected || selected.contains(modifier_name) {
+                merged_entry.modifiers.insert(modifier_name.clone(), new_modifier);
+                continue;
+            }
+
+            // A missing cell is read by the game as 100.
+            let modifier = merged_entry.modifiers.get(modifier_name).copied().unwrap_or(100.0);
+            if modifier.round() != new_modifier.round() {
+                inconsistencies.push(format!("{} vs {} is {} rather than {}", entry_name, modifier_name,
+                                             modifier.round(), new_modifier.round()));
+            }
+        }
+    }
+
+    (merged_unit_balance, inconsistencies)
+}
+
+fn glob_matches(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+
+    // Greedy matching, backtracking to the most recent `*`.
+    let (mut p, mut n) = (0, 0);
+    let mut backtrack = None;
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, n));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match backtrack {
+                Some((star_p, star_n)) => {
+                    backtrack = Some((star_p, star_n + 1));
+                    p = star_p + 1;
+                    n = star_n + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == '*')
+}
@@ -0,0 +1,157 @@
+//! Targeted update of a flattened balance file after unitrules.xml has
+//! changed.
+
+use std::path::Path;
+
+use fnv::FnvHashSet;
+
+use crate::{
+    encoding, flatten_table, objmask_to_string, overrides, parse_balance_document, parse_balance_xml, parse_options,
+    parse_unitrules, parse_unitrules_xml, patch, provenance, write_new_document, MergePolicy, Options, UnitBalance,
+};
+
+/// Options that select the units to recalculate, which `update` chooses
+/// itself.
+const SUBSET_OPTIONS: [&str; 3] = ["--only", "--only-flag", "--base"];
+
+pub fn run_update(args: &[String]) -> Result<(), String> {
+    let usage = "Usage: update [options] <old unitrules file> <new unitrules file> <source balance file> \
+                 <flattened balance file>";
+
+    let (option_args, paths) = args.split_at(args.len().saturating_sub(4));
+    let (old_unit_rules_path, new_unit_rules_path, source_balance_path, flat_balance_path) = match paths {
+        [old_unitrules, new_unitrules, source, flat] if !paths.iter().any(|path| path.starts_with("--")) => {
+            (Path::new(old_unitrules), Path::new(new_unitrules), Path::new(source), Path::new(flat))
+        }
+        _ => return Err(usage.to_owned()),
+    };
+
+    if let Some(option) = option_args.iter().find(|arg| SUBSET_OPTIONS.contains(&arg.as_str())) {
+        return Err(format!("{} cannot be used with update, which recalculates the units that changed", option));
+    }
+
+    let (mut document, input_encoding) = parse_balance_document(flat_balance_path, MergePolicy::default())?;
+    let options = update_options(&document.header, option_args, flat_balance_path)?;
+
+    let old_unit_objmask_map = parse_unitrules(old_unit_rules_path)?;
+    let new_unit_rules_bytes = encoding::read_file(new_unit_rules_path, "unitrules.xml")?;
+    let (new_unit_rules_xml, _) = encoding::decode_xml(&new_unit_rules_bytes, "unitrules.xml")?;
+    let new_unit_objmask_map = parse_unitrules_xml(&new_unit_rules_xml)?;
+
+    let source_bytes = encoding::read_file(source_balance_path, "balance.xml")?;
+    let (source_xml, _) = encoding::decode_xml(&source_bytes, "balance.xml")?;
+    let source_document = parse_balance_xml(&source_xml, options.merge)?;
+
+    let mut affected = FnvHashSet::default();
+    for (unit, new_objmask) in &new_unit_objmask_map {
+        match old_unit_objmask_map.get(unit) {
+            None => {
+                eprintln!("Added {} ({})", unit, objmask_to_string(new_objmask));
+                affected.insert(unit.clone());
+            }
+            Some(old_objmask) if old_objmask != new_objmask => {
+                eprintln!("Changed {}: OBJ_MASK {} -> {}", unit, objmask_to_string(old_objmask),
+                          objmask_to_string(new_objmask));
+                affected.insert(unit.clone());
+            }
+            Some(_) => (),
+        }
+    }
+
+    let removed: Vec<&String> = old_unit_objmask_map.keys()
+        .filter(|unit| !new_unit_objmask_map.contains_key(*unit))
+        .collect();
+    for unit in &removed {
+        eprintln!("Removed {}", unit);
+    }
+
+    if affected.is_empty() && removed.is_empty() {
+        eprintln!("No units changed");
+    }
+
+    let patches = options.patches.iter()
+        .map(|patch_path| patch::parse_patch(patch_path))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let overrides = options.overrides.iter()
+        .map(|overrides_path| overrides::parse_overrides(overrides_path))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let mut changed_cells = 0;
+    for table_index in document.select_tables(&options.tables)? {
+        if document.tables.len() > 1 {
+            eprintln!("Processing {}", document.describe_table(table_index));
+        }
+
+        let source_table_index = source_document.matching_table(&document, table_index).ok_or_else(|| {
+            format!("No {} in the source balance file", document.describe_table(table_index))
+        })?;
+
+        let table = &mut document.tables[table_index];
+        let mut flat_unit_balance = table.unit_balance.clone();
+        for unit in &removed {
+            flat_unit_balance.entries.shift_remove(unit.as_str());
+            for entry in flat_unit_balance.entries.values_mut() {
+                entry.modifiers.shift_remove(unit.as_str());
+            }
+        }
+
+        let source_unit_balance = source_document.tables[source_table_index].unit_balance.clone();
+        let new_unit_balance = flatten_table(&new_unit_objmask_map, source_unit_balance, Some(flat_unit_balance),
+                                             &patches, &overrides, &options.balance, Some(&affected), false)?;
+
+        changed_cells += count_changed_cells(&table.unit_balance, &new_unit_balance);
+        table.unit_balance = new_unit_balance;
+    }
+    eprintln!("{} cells changed", changed_cells);
+
+    document.header = provenance::header(&source_bytes, &new_unit_rules_bytes, &patches, &overrides, None,
+                                         &new_unit_objmask_map, &options);
+
+    write_new_document(&mut std::io::stdout(), &document, options.output_encoding.unwrap_or(input_encoding),
+                       &options.format)
+}
+
+/// The options the flattened balance file was generated with, from its
+/// provenance header, followed by `option_args` from the command line.
+fn update_options(header: &[String], option_args: &[String], flat_balance_path: &Path) -> Result<Options, String> {
+    let mut arguments = Vec::new();
+    if header.is_empty() {
+        eprintln!("Warning: {} has no provenance header, it is assumed to have been flattened with the options \
+                   given", flat_balance_path.display());
+    } else {
+        let mut recorded = provenance::recorded_arguments(header)?.into_iter();
+        while let Some(argument) = recorded.next() {
+            if SUBSET_OPTIONS.contains(&argument.as_str()) {
+                recorded.next();
+            } else {
+                arguments.push(argument);
+            }
+        }
+    }
+
+    arguments.extend_from_slice(option_args);
+
+    let (options, balance_xml_path) = parse_options(&arguments)?;
+    if let Some(arg) = balance_xml_path {
+        return Err(format!("Unexpected argument \"{}\"", arg));
+    }
+    if options.unit_rules.is_some() {
+        return Err("--unitrules cannot be used with update, which reads both unitrules.xml files".to_owned());
+    }
+
+    Ok(options)
+}
+
+/// Count the cells of `new_unit_balance` that are missing from or
+/// different in `old_unit_balance`.
+fn count_changed_cells(old_unit_balance: &UnitBalance, new_unit_balance: &UnitBalance) -> usize {
+    new_unit_balance.entries.iter()
+        .flat_map(|(entry_name, entry)| entry.modifiers.iter().map(move |(name, modifier)| (entry_name, name, modifier)))
+        .filter(|&(entry_name, modifier_name, modifier)| {
+            let old_modifier = old_unit_balance.entries.get(entry_name)
+                .and_then(|entry| entry.modifiers.get(modifier_name));
+            old_modifier.is_none_or(|old_modifier| old_modifier.round() != modifier.round())
+        })
+        .count()
+}
@@ -0,0 +1,104 @@
+mod common;
+
+use common::{cell, fixture, run};
+use ron_objmask_workaround::combine::{combiner_by_name, Combiner, Factor, COMBINERS};
+
+/// Flatten the combine fixture with the given combine mode, returning the
+/// Knight vs Pikeman and Pikeman vs Knight cells.
+///
+/// Knight (MW) vs Pikeman (FW5) picks up the direct cell (120), MOUNTED vs
+/// PIKE (50) and MELEE vs PIKE (80). Pikeman vs Knight has no direct cell
+/// and picks up PIKE vs MOUNTED (150) and FOOT vs MOUNTED (120).
+fn flatten(mode: &str) -> (String, String) {
+    let balance = fixture("combine/balance.xml");
+    let output = run(&["--combine", mode, balance.to_str().unwrap()]);
+    (cell(&output, "Knight", "Pikeman"), cell(&output, "Pikeman", "Knight"))
+}
+
+#[test]
+fn multiply_is_the_default() {
+    let balance = fixture("combine/balance.xml");
+    let output = run(&[balance.to_str().unwrap()]);
+    assert_eq!(cell(&output, "Knight", "Pikeman"), "48");
+    assert_eq!(cell(&output, "Pikeman", "Knight"), "180");
+}
+
+#[test]
+fn multiply() {
+    // 100 * 1.2 * 0.5 * 0.8 and 100 * 1.5 * 1.2
+    assert_eq!(flatten("multiply"), ("48".to_owned(), "180".to_owned()));
+}
+
+#[test]
+fn add() {
+    // 100 + 20 - 50 - 20 and 100 + 50 + 20
+    assert_eq!(flatten("add"), ("50".to_owned(), "170".to_owned()));
+}
+
+#[test]
+fn strongest() {
+    // The factor furthest from 100 on each side.
+    assert_eq!(flatten("strongest"), ("50".to_owned(), "150".to_owned()));
+}
+
+#[test]
+fn direct() {
+    // The direct cell replaces the objmask factors, which still multiply
+    // where there is no direct cell.
+    assert_eq!(flatten("direct"), ("120".to_owned(), "180".to_owned()));
+}
+
+#[test]
+fn objmask_cells_are_reset() {
+    let balance = fixture("combine/balance.xml");
+    let output = run(&["--combine", "add", balance.to_str().unwrap()]);
+    assert_eq!(cell(&output, "Flag_M_OBJMASK_MOUNTED", "Pikeman"), "100");
+    assert_eq!(cell(&output, "Knight", "Flag_5_OBJMASK_PIKE"), "100");
+}
+
+/// The factors Knight vs Pikeman picks up in the combine fixture.
+fn knight_vs_pikeman() -> [Factor<'static>; 3] {
+    let factor = |attacker, target, modifier| Factor { attacker, target, modifier, direct: attacker == "Knight" };
+    [
+        factor("Knight", "Pikeman", 120.0),
+        factor("Flag_M_OBJMASK_MOUNTED", "Flag_5_OBJMASK_PIKE", 50.0),
+        factor("Flag_W_OBJMASK_MELEE", "Flag_5_OBJMASK_PIKE", 80.0),
+    ]
+}
+
+#[test]
+fn combiners_are_available_from_the_library() {
+    let factors = knight_vs_pikeman();
+    let combined: Vec<(&str, f32)> = COMBINERS.iter()
+        .map(|combiner| (combiner.name(), combiner.combine(&factors).round()))
+        .collect();
+    assert_eq!(combined, [("multiply", 48.0), ("add", 50.0), ("strongest", 50.0), ("direct", 120.0)]);
+
+    for combiner in &COMBINERS {
+        assert_eq!(combiner.combine(&[]), 100.0, "{}", combiner.name());
+    }
+    assert!(combiner_by_name("average").is_none());
+}
+
+/// Apply only the factor closest to 100.
+#[derive(Debug)]
+struct Weakest;
+
+impl Combiner for Weakest {
+    fn name(&self) -> &'static str {
+        "weakest"
+    }
+
+    fn combine(&self, factors: &[Factor]) -> f32 {
+        factors.iter()
+            .map(|factor| factor.modifier)
+            .min_by(|a, b| (a - 100.0).abs().total_cmp(&(b - 100.0).abs()))
+            .unwrap_or(100.0)
+    }
+}
+
+#[test]
+fn combiners_can_be_implemented_outside_the_crate() {
+    assert_eq!(Weakest.combine(&knight_vs_pikeman()), 120.0);
+    assert_eq!(Weakest.combine(&[]), 100.0);
+}
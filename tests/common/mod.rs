@@ -0,0 +1,78 @@
+#![allow(dead_code)]
+
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output, Stdio};
+
+pub fn fixture(path: &str) -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("tests").join("fixtures").join(path)
+}
+
+/// The unitrules.xml shared by most tests, with Knight (MW), Pikeman (FW5)
+/// and Archer (FKR).
+pub fn unit_rules() -> String {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("tests").join("common").join("unitrules.xml")
+        .to_str().unwrap().to_owned()
+}
+
+/// The path of a file in the test scratch directory.
+pub fn scratch_path(name: &str) -> PathBuf {
+    PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join(name)
+}
+
+/// Write `contents` to a file in the test scratch directory.
+pub fn scratch_file(name: &str, contents: &str) -> PathBuf {
+    let path = scratch_path(name);
+    std::fs::write(&path, contents).expect("failed to write scratch file");
+    path
+}
+
+/// Run the tool with `args`, returning its standard output.
+pub fn run(args: &[&str]) -> String {
+    String::from_utf8(run_bytes(args)).expect("output is not UTF-8")
+}
+
+/// Run the tool with `args`, returning its raw standard output.
+pub fn run_bytes(args: &[&str]) -> Vec<u8> {
+    let output = output(args);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    output.stdout
+}
+
+/// Run the tool with `args`, whether or not it succeeds.
+pub fn output(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_ron-objmask-workaround"))
+        .args(args)
+        .stdin(Stdio::null())
+        .output()
+        .expect("failed to run ron-objmask-workaround")
+}
+
+/// Run the tool with `args` and `stdin` piped to its standard input,
+/// returning its standard output.
+pub fn run_with_stdin(args: &[&str], stdin: &[u8]) -> String {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_ron-objmask-workaround"))
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("failed to run ron-objmask-workaround");
+    child.stdin.take().unwrap().write_all(stdin).expect("failed to write standard input");
+
+    let output = child.wait_with_output().expect("failed to run ron-objmask-workaround");
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    String::from_utf8(output.stdout).expect("output is not UTF-8")
+}
+
+/// Find the value of `attacker` vs `target` in a written balance file.
+pub fn cell(balance_xml: &str, attacker: &str, target: &str) -> String {
+    let entry = balance_xml.lines()
+        .find(|line| line.contains(&format!("<ENTRY name=\"{}\"", attacker)))
+        .unwrap_or_else(|| panic!("no entry for {}", attacker));
+
+    let key = format!(" {}=\"", target);
+    let start = entry.find(&key).unwrap_or_else(|| panic!("no cell for {} vs {}", attacker, target)) + key.len();
+    let len = entry[start..].find('"').unwrap();
+    entry[start..start + len].to_owned()
+}
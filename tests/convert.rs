@@ -0,0 +1,87 @@
+mod common;
+
+#[cfg(feature = "serde")]
+use std::path::Path;
+
+use common::output;
+#[cfg(feature = "serde")]
+use common::{fixture, run, scratch_file, scratch_path, unit_rules};
+
+#[cfg(feature = "serde")]
+fn path(path: &Path) -> &str {
+    path.to_str().unwrap()
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn balance_files_round_trip_through_json() {
+    let balance = fixture("overrides/balance.xml");
+    let json = scratch_path("convert_round_trip.json");
+    let xml = scratch_path("convert_round_trip.xml");
+
+    run(&["convert", path(&balance), path(&json)]);
+    run(&["convert", path(&json), path(&xml)]);
+
+    assert_eq!(std::fs::read_to_string(&xml).unwrap(), std::fs::read_to_string(&balance).unwrap());
+}
+
+/// Only the cells of the first table can be converted, and anything else
+/// left out is warned about.
+#[cfg(feature = "serde")]
+#[test]
+fn discarded_content_is_warned_about() {
+    let balance = fixture("document/balance.xml");
+    let result = output(&["convert", path(&balance), "-"]);
+    assert!(result.status.success());
+
+    let stderr = String::from_utf8(result.stderr).unwrap();
+    assert!(stderr.contains("Warning: Only the first of the 2 tables"), "{}", stderr);
+    assert!(stderr.contains("Warning: Comments and other content outside the entries"), "{}", stderr);
+
+    let entry = "<ROOT>\n  <ENTRY name=\"Knight\" Pikeman=\"120\" note=\"fast\">\n    <NOTE/>\n  </ENTRY>\n</ROOT>\n";
+    let entry = scratch_file("convert_entry.xml", entry);
+    let result = output(&["convert", path(&entry), "-"]);
+    let stderr = String::from_utf8(result.stderr).unwrap();
+    assert!(stderr.contains("Warning: Attributes that are not numbers"), "{}", stderr);
+    assert!(stderr.contains("Warning: The content of ENTRY elements"), "{}", stderr);
+    assert!(!stderr.contains("tables"), "{}", stderr);
+
+    // Written back as XML, entries keep their other content.
+    let xml = scratch_path("convert_entry_copy.xml");
+    let result = output(&["convert", path(&entry), path(&xml)]);
+    assert!(!String::from_utf8(result.stderr).unwrap().contains("Warning"));
+    assert!(std::fs::read_to_string(&xml).unwrap().contains("note=\"fast\""));
+}
+
+/// Flags are written by their full name and may be read back by their
+/// character code.
+#[cfg(feature = "serde")]
+#[test]
+fn units_are_converted_with_their_flags() {
+    let json = run(&["convert", "--units", &unit_rules(), "-"]);
+    assert!(json.contains("\"Archer\": [\n    \"Flag_F_OBJMASK_FOOT\",\n    \"Flag_K_OBJMASK_FOOT_ARCHER\",\n    \
+                           \"Flag_R_OBJMASK_ARCHERY\"\n  ]"), "{}", json);
+
+    let codes = scratch_file("convert_units.json", "{\"Knight\": [\"M\", \"Flag_W_OBJMASK_MELEE\"]}");
+    let toml = scratch_path("convert_units.toml");
+    run(&["convert", "--units", path(&codes), path(&toml)]);
+    let toml = std::fs::read_to_string(&toml).unwrap();
+    assert_eq!(toml, "Knight = [\"Flag_M_OBJMASK_MOUNTED\", \"Flag_W_OBJMASK_MELEE\"]\n\n");
+
+    let unknown = scratch_file("convert_units_unknown.json", "{\"Knight\": [\"Heavy\"]}");
+    let result = output(&["convert", "--units", path(&unknown), "-"]);
+    assert!(!result.status.success());
+    assert!(String::from_utf8(result.stderr).unwrap().contains("unknown OBJ_MASK flag \"Heavy\" for unit \"Knight\""));
+
+    let result = output(&["convert", "--units", &unit_rules(), path(&scratch_path("convert_units.xml"))]);
+    assert!(!result.status.success());
+}
+
+#[cfg(not(feature = "serde"))]
+#[test]
+fn converting_requires_the_serde_feature() {
+    let result = output(&["convert", "balance.xml", "balance.json"]);
+    assert!(!result.status.success());
+    let stderr = String::from_utf8(result.stderr).unwrap();
+    assert!(stderr.contains("The convert command requires the \"serde\" feature"), "{}", stderr);
+}
@@ -0,0 +1,63 @@
+mod common;
+
+use common::{fixture, run, scratch_file, unit_rules};
+
+/// `fmt` keeps comments, ROOT attributes, other elements and ENTRY
+/// elements outside of a TABLE where they were.
+#[test]
+fn fmt_keeps_other_content() {
+    let balance = fixture("document/balance.xml");
+    let output = run(&["fmt", balance.to_str().unwrap()]);
+    let original = std::fs::read_to_string(&balance).unwrap();
+    assert_eq!(output, original);
+
+    run(&["fmt", "--check", balance.to_str().unwrap()]);
+}
+
+#[test]
+fn flattening_keeps_other_content() {
+    let balance = fixture("document/balance.xml");
+    let output = run(&["--unitrules", &unit_rules(), "--table", "land", balance.to_str().unwrap()]);
+
+    let expected = [
+        "<!-- Balance for the test mod -->\n<ROOT version=\"2\" author=\"a &amp; b\">",
+        "  <!-- Loose entries -->\n  <ENTRY name=\"Knight\" Pikeman=\"120\"/>",
+        "  <META>\n    <NOTE>kept</NOTE>\n  </META>\n  <TABLE name=\"land\">",
+        "    <!-- Cavalry -->\n    <ENTRY name=\"Flag_M_OBJMASK_MOUNTED\"",
+        "    <!-- End of land -->\n  </TABLE>\n  <!-- The end -->\n</ROOT>\n",
+    ];
+    for expected in &expected {
+        assert!(output.contains(expected), "{} not in {}", expected, output);
+    }
+    assert_eq!(output.matches("<TABLE").count(), 1);
+}
+
+/// Kept content spanning several lines takes the line endings of the
+/// output, whatever those of the input.
+#[test]
+fn kept_content_takes_the_output_line_endings() {
+    let balance = fixture("document/balance.xml");
+    let output = run(&["fmt", "--crlf", balance.to_str().unwrap()]);
+    assert!(output.contains("<META>\r\n    <NOTE>kept</NOTE>\r\n  </META>"), "{}", output);
+    assert_eq!(output.matches('\n').count(), output.matches("\r\n").count(), "{}", output);
+
+    let balance = scratch_file("document_crlf.xml", "<?xml version=\"1.0\"?>\r\n<ROOT>\r\n  <TABLE>\r\n    \
+        <ENTRY name=\"Knight\" Pikeman=\"50\">\r\n      <NOTE>\r\n        kept\r\n      </NOTE>\r\n    \
+        </ENTRY>\r\n  </TABLE>\r\n</ROOT>\r\n");
+    let output = run(&["fmt", balance.to_str().unwrap()]);
+    assert!(!output.contains('\r'), "{:?}", output);
+    assert!(output.contains("<ENTRY name=\"Knight\" Pikeman=\"50\">\n      <NOTE>\n        kept\n      </NOTE>\n    \
+                             </ENTRY>"), "{}", output);
+
+    let output = run(&["fmt", "--compact", balance.to_str().unwrap()]);
+    assert!(output.contains("<ENTRY name=\"Knight\" Pikeman=\"50\"><NOTE> kept </NOTE></ENTRY>"), "{}", output);
+}
+
+/// Written files end with a line ending, even on one line.
+#[test]
+fn output_ends_with_a_line_ending() {
+    let balance = fixture("document/balance.xml");
+    let output = run(&["fmt", "--compact", "--crlf", balance.to_str().unwrap()]);
+    assert!(output.ends_with("</ROOT>\r\n"), "{}", output);
+    assert_eq!(output.matches("\r\n").count(), 1, "{}", output);
+}
@@ -0,0 +1,82 @@
+mod common;
+
+use common::{cell, fixture, run_bytes};
+
+use encoding_rs::{Encoding, UTF_16BE, UTF_16LE, UTF_8, WINDOWS_1252};
+
+/// Flatten the balance fixture in `dir`, returning the raw output.
+///
+/// Each fixture has a Légionnaire (FHW) with a direct cell of 120 against
+/// Cavalry (MW), and HEAVY_INF vs MOUNTED of 150, flattening to 180.
+fn flatten(dir: &str, args: &[&str]) -> Vec<u8> {
+    let balance = fixture(&format!("encoding/{}/balance.xml", dir));
+    let mut args = args.to_vec();
+    args.push(balance.to_str().unwrap());
+    run_bytes(&args)
+}
+
+/// Decode output in `encoding`, checking it has no unexpected characters.
+fn decode(output: &[u8], encoding: &'static Encoding) -> String {
+    let (text, had_errors) = encoding.decode_without_bom_handling(output);
+    assert!(!had_errors, "output is not {}", encoding.name());
+    text.into_owned()
+}
+
+#[test]
+fn utf8_with_bom() {
+    let output = flatten("utf8_bom", &[]);
+    assert_eq!(&output[..3], b"\xEF\xBB\xBF");
+    let output = decode(&output[3..], UTF_8);
+    assert!(output.starts_with("<?xml version=\"1.0\"?>"));
+    assert_eq!(cell(&output, "Légionnaire", "Cavalry"), "180");
+}
+
+#[test]
+fn utf16_little_endian() {
+    let output = flatten("utf16le", &[]);
+    assert_eq!(&output[..2], b"\xFF\xFE");
+    let output = decode(&output[2..], UTF_16LE);
+    assert!(output.starts_with("<?xml version=\"1.0\" encoding=\"UTF-16\"?>"));
+    assert_eq!(cell(&output, "Légionnaire", "Cavalry"), "180");
+}
+
+#[test]
+fn utf16_big_endian() {
+    let output = flatten("utf16be", &[]);
+    assert_eq!(&output[..2], b"\xFE\xFF");
+    let output = decode(&output[2..], UTF_16BE);
+    assert!(output.starts_with("<?xml version=\"1.0\" encoding=\"UTF-16\"?>"));
+    assert_eq!(cell(&output, "Légionnaire", "Cavalry"), "180");
+}
+
+#[test]
+fn windows_1252() {
+    let output = flatten("windows1252", &[]);
+    assert!(std::str::from_utf8(&output).is_err());
+    let output = decode(&output, WINDOWS_1252);
+    assert!(output.starts_with("<?xml version=\"1.0\" encoding=\"windows-1252\"?>"));
+    assert_eq!(cell(&output, "Légionnaire", "Cavalry"), "180");
+}
+
+#[test]
+fn undeclared_windows_1252() {
+    // Not valid UTF-8 and without an XML declaration naming the encoding.
+    let output = decode(&flatten("undeclared1252", &[]), WINDOWS_1252);
+    assert!(output.starts_with("<?xml version=\"1.0\" encoding=\"windows-1252\"?>"));
+    assert_eq!(cell(&output, "Légionnaire", "Cavalry"), "180");
+}
+
+#[test]
+fn chosen_output_encoding() {
+    let output = flatten("utf16le", &["--output-encoding", "utf-8"]);
+    let output = String::from_utf8(output).expect("output is not UTF-8");
+    assert!(output.starts_with("<?xml version=\"1.0\"?>"));
+    assert_eq!(cell(&output, "Légionnaire", "Cavalry"), "180");
+
+    let output = flatten("utf8_bom", &["--output-encoding", "windows-1252"]);
+    assert!(output.starts_with(b"<?xml version=\"1.0\" encoding=\"windows-1252\"?>"));
+
+    let output = flatten("utf8_bom", &["--output-encoding", "utf-16be"]);
+    assert_eq!(&output[..2], b"\xFE\xFF");
+    assert_eq!(cell(&decode(&output[2..], UTF_16BE), "Légionnaire", "Cavalry"), "180");
+}
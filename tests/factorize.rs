@@ -0,0 +1,29 @@
+mod common;
+
+use common::{cell, fixture, run, scratch_file};
+
+/// The balance tables of a written file, without the provenance header.
+fn tables(balance_xml: &str) -> &str {
+    &balance_xml[balance_xml.find("<ROOT>").expect("no ROOT element")..]
+}
+
+/// The fixture has Knight vs Archer at 87.5, scaled by MOUNTED vs
+/// FOOT_ARCHER (150), and LightCavalry vs Knight at 87.5 with no factor
+/// applying, so it is written back out as 87.5.
+#[test]
+fn flatten_factorize_flatten_round_trip() {
+    let unit_rules = fixture("factorize/unitrules.xml");
+    let unit_rules = unit_rules.to_str().unwrap();
+
+    let flattened = run(&[fixture("factorize/balance.xml").to_str().unwrap()]);
+    assert_eq!(cell(&flattened, "Knight", "Archer"), "131");
+    assert_eq!(cell(&flattened, "LightCavalry", "Knight"), "87.5");
+    let flattened_path = scratch_file("factorize_flattened.xml", &flattened);
+
+    let source = run(&["factorize", flattened_path.to_str().unwrap(), unit_rules]);
+    assert!(!source.contains("=\"100\""), "neutral override in {}", source);
+    let source_path = scratch_file("factorize_source.xml", &source);
+
+    let reflattened = run(&["--unitrules", unit_rules, source_path.to_str().unwrap()]);
+    assert_eq!(tables(&reflattened), tables(&flattened));
+}
@@ -0,0 +1,69 @@
+mod common;
+
+use common::{cell, fixture, output, run, unit_rules};
+
+/// Flatten the overrides fixture, which has MOUNTED vs PIKE 50, MOUNTED vs
+/// FOOT_ARCHER 300, PIKE vs MOUNTED 200 and ARCHERY vs MELEE 150.
+fn flatten(args: &[&str]) -> String {
+    let unit_rules = unit_rules();
+    let balance = fixture("overrides/balance.xml");
+    let mut all_args = vec!["--unitrules", &unit_rules];
+    all_args.extend_from_slice(args);
+    all_args.push(balance.to_str().unwrap());
+    run(&all_args)
+}
+
+/// An unexpanded flag keeps its row and column, holding its interactions
+/// with each unit, and no longer applies to the units that have it.
+#[test]
+fn unexpanded_flags_are_written_per_unit() {
+    let stdout = flatten(&["--exclude-flags", "5"]);
+    assert!(stdout.contains("Expanded flags: A B C D E F G H I J K L M N O P Q R S T U V W X Y Z 1 2 3 4 6"),
+            "{}", stdout);
+
+    assert_eq!(cell(&stdout, "Knight", "Flag_5_OBJMASK_PIKE"), "50");
+    assert_eq!(cell(&stdout, "Flag_5_OBJMASK_PIKE", "Knight"), "200");
+    assert_eq!(cell(&stdout, "Knight", "Pikeman"), "100");
+    assert_eq!(cell(&stdout, "Pikeman", "Knight"), "100");
+    assert_eq!(cell(&stdout, "Knight", "Archer"), "300");
+
+    // Expanded flags are still reset.
+    assert_eq!(cell(&stdout, "Flag_M_OBJMASK_MOUNTED", "Flag_5_OBJMASK_PIKE"), "100");
+    assert_eq!(cell(&stdout, "Flag_5_OBJMASK_PIKE", "Flag_M_OBJMASK_MOUNTED"), "100");
+}
+
+/// Cells between two unexpanded flags are kept as they were.
+#[test]
+fn only_included_flags_are_expanded() {
+    let stdout = flatten(&["--include-flags", "K, Flag_R_OBJMASK_ARCHERY"]);
+    assert!(stdout.contains("Expanded flags: K R"), "{}", stdout);
+
+    assert_eq!(cell(&stdout, "Flag_M_OBJMASK_MOUNTED", "Flag_5_OBJMASK_PIKE"), "50");
+    assert_eq!(cell(&stdout, "Flag_5_OBJMASK_PIKE", "Flag_M_OBJMASK_MOUNTED"), "200");
+    assert_eq!(cell(&stdout, "Flag_M_OBJMASK_MOUNTED", "Archer"), "300");
+    assert_eq!(cell(&stdout, "Archer", "Flag_W_OBJMASK_MELEE"), "150");
+    assert_eq!(cell(&stdout, "Knight", "Pikeman"), "100");
+}
+
+/// With both options, the excluded flags are taken out of the included
+/// ones.
+#[test]
+fn excluded_flags_are_taken_from_the_included_flags() {
+    let stdout = flatten(&["--include-flags", "M,5", "--exclude-flags", "Flag_5_OBJMASK_PIKE"]);
+    assert!(stdout.contains("Expanded flags: M"), "{}", stdout);
+    assert_eq!(cell(&stdout, "Knight", "Flag_5_OBJMASK_PIKE"), "50");
+    assert_eq!(cell(&stdout, "Flag_5_OBJMASK_PIKE", "Knight"), "200");
+
+    let stdout = flatten(&["--include-flags", "M", "--exclude-flags", "M"]);
+    assert!(stdout.contains("Expanded flags: none"), "{}", stdout);
+}
+
+#[test]
+fn unknown_flags_are_rejected() {
+    for option in ["--include-flags", "--exclude-flags"] {
+        let result = output(&["--unitrules", &unit_rules(), option, "M,Heavy", "balance.xml"]);
+        assert!(!result.status.success());
+        let stderr = String::from_utf8(result.stderr).unwrap();
+        assert!(stderr.contains("Unknown OBJ_MASK flag \"Heavy\""), "{}", stderr);
+    }
+}
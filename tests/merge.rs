@@ -0,0 +1,46 @@
+mod common;
+
+use common::{cell, fixture, run, unit_rules};
+
+fn merge(policy: &str) -> String {
+    let balance = fixture("merge/balance.xml");
+    run(&["--unitrules", &unit_rules(), "--merge", policy, balance.to_str().unwrap()])
+}
+
+/// A repeated attribute is resolved by the policy even when only one of
+/// its values is a number. Numeric `note` cells name no unit, so they are
+/// left out of the flattened table.
+#[test]
+fn mixed_repeats_keep_the_first_value() {
+    let output = merge("first");
+    assert_eq!(cell(&output, "Knight", "note"), "abc");
+    assert!(!output.contains("see Knight"), "{}", output);
+    assert_eq!(cell(&output, "Pikeman", "Knight"), "60");
+}
+
+#[test]
+fn mixed_repeats_keep_the_last_value() {
+    let output = merge("last");
+    assert!(!output.contains("abc"), "{}", output);
+    assert_eq!(cell(&output, "Pikeman", "note"), "see Knight");
+    assert_eq!(cell(&output, "Pikeman", "Knight"), "80");
+}
+
+/// Values that are not numbers cannot be multiplied, so the last is kept.
+#[test]
+fn mixed_repeats_are_not_multiplied() {
+    let output = merge("multiply");
+    assert!(!output.contains("abc"), "{}", output);
+    assert_eq!(cell(&output, "Pikeman", "note"), "see Knight");
+    assert_eq!(cell(&output, "Pikeman", "Knight"), "48");
+}
+
+/// `fmt` writes each attribute once.
+#[test]
+fn fmt_writes_mixed_repeats_once() {
+    let balance = fixture("merge/balance.xml");
+    let output = run(&["fmt", balance.to_str().unwrap()]);
+    assert_eq!(output.matches(" note=").count(), 2, "{}", output);
+    assert_eq!(cell(&output, "Knight", "note"), "50");
+    assert_eq!(cell(&output, "Pikeman", "note"), "see Knight");
+}
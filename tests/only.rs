@@ -0,0 +1,40 @@
+mod common;
+
+use common::{cell, fixture, output, unit_rules};
+
+/// Run the tool on the only fixture with `args`, returning its standard
+/// output and standard error.
+fn run_only(args: &[&str]) -> (String, String) {
+    let unit_rules = unit_rules();
+    let balance = fixture("only/balance.xml");
+    let mut all_args = vec!["--unitrules", &unit_rules];
+    all_args.extend_from_slice(args);
+    all_args.push(balance.to_str().unwrap());
+
+    let output = output(&all_args);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    (String::from_utf8(output.stdout).unwrap(), String::from_utf8(output.stderr).unwrap())
+}
+
+/// Without `--base`, cells are carried over from the unflattened input,
+/// where a missing cell is 100 just as the recalculation gives. Only the
+/// flag cell itself differs.
+#[test]
+fn missing_base_cells_are_100() {
+    let (stdout, stderr) = run_only(&["--only", "Knight"]);
+    assert_eq!(cell(&stdout, "Knight", "Pikeman"), "50");
+    assert!(stderr.contains("Warning: 1 cells carried over"), "{}", stderr);
+    assert!(stderr.contains("Flag_M_OBJMASK_MOUNTED vs Flag_5_OBJMASK_PIKE is 50 rather than 100"), "{}", stderr);
+}
+
+/// Only overrides of recalculated cells are reported, and overridden cells
+/// that were carried over are reported as differing.
+#[test]
+fn overrides_outside_the_selection_are_not_reported() {
+    let overrides = fixture("only/overrides.txt");
+    let (stdout, stderr) = run_only(&["--only", "Knight", "--overrides", overrides.to_str().unwrap()]);
+    assert_eq!(cell(&stdout, "Knight", "Pikeman"), "70");
+    assert!(stderr.contains("Override: Knight vs Pikeman: 50 -> 70"), "{}", stderr);
+    assert!(!stderr.contains("Override: Pikeman vs Archer"), "{}", stderr);
+    assert!(stderr.contains("Pikeman vs Archer is 100 rather than 150"), "{}", stderr);
+}
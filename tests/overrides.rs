@@ -0,0 +1,99 @@
+mod common;
+
+use common::{cell, fixture, output, scratch_file, scratch_path, unit_rules};
+
+/// Flatten the overrides fixture with the overrides file at `overrides`,
+/// returning its standard output, standard error and whether it succeeded.
+fn flatten(overrides: &str) -> (String, String, bool) {
+    let balance = fixture("overrides/balance.xml");
+    let result = output(&["--unitrules", &unit_rules(), "--overrides", overrides, balance.to_str().unwrap()]);
+    (String::from_utf8(result.stdout).unwrap(), String::from_utf8(result.stderr).unwrap(), result.status.success())
+}
+
+/// Without overrides, the fixture flattens to Knight vs Pikeman 50, Knight
+/// vs Archer 300, Pikeman vs Knight 200 and Archer vs Knight and Pikeman
+/// 150, with every other cell 100.
+#[test]
+fn every_operation_is_applied_in_order() {
+    let overrides = fixture("overrides/overrides.txt");
+    let (stdout, stderr, success) = flatten(overrides.to_str().unwrap());
+    assert!(success, "{}", stderr);
+
+    assert_eq!(cell(&stdout, "Pikeman", "Knight"), "100");
+    assert_eq!(cell(&stdout, "Archer", "Knight"), "75");
+    assert_eq!(cell(&stdout, "Knight", "Archer"), "250");
+    assert_eq!(cell(&stdout, "Pikeman", "Pikeman"), "120");
+    assert_eq!(cell(&stdout, "Archer", "Pikeman"), "150");
+    assert_eq!(cell(&stdout, "Knight", "Knight"), "80");
+    assert_eq!(cell(&stdout, "Archer", "Archer"), "110");
+    assert_eq!(cell(&stdout, "Knight", "Pikeman"), "50");
+
+    let reported: Vec<&str> = stderr.lines().filter_map(|line| line.strip_prefix("Override: ")).collect();
+    assert_eq!(reported, [
+        "Knight vs Knight: 100 -> 50 (line 2)",
+        "Pikeman vs Knight: 200 -> 100 (line 2)",
+        "Archer vs Knight: 150 -> 75 (line 2)",
+        "Knight vs Archer: 300 -> 250 (line 3)",
+        "Pikeman vs Pikeman: 100 -> 120 (line 4)",
+        "Knight vs Knight: 50 -> 80 (line 5)",
+        "Archer vs Archer: 100 -> 110 (line 7)",
+    ]);
+}
+
+/// Rules for every unit leave out meta entries such as the ages.
+#[test]
+fn meta_entries_are_not_overridden() {
+    let overrides = scratch_file("overrides_meta.txt", "* vs *: set 50\n");
+    let (stdout, stderr, success) = flatten(overrides.to_str().unwrap());
+    assert!(success, "{}", stderr);
+
+    assert_eq!(cell(&stdout, "Knight", "Archer"), "50");
+    assert_eq!(cell(&stdout, "AGE_1", "Knight"), "100");
+    assert_eq!(cell(&stdout, "Knight", "SIEGE"), "100");
+    // Knight vs Pikeman is already 50.
+    assert_eq!(stderr.matches("Override: ").count(), 8, "{}", stderr);
+}
+
+/// Overrides files may be UTF-16 with a byte order mark or Windows-1252.
+#[test]
+fn overrides_can_be_in_other_encodings() {
+    let text = "# R\u{e9}glage\r\nKnight vs Archer: set 250\r\n";
+
+    let utf16 = scratch_path("overrides_utf16be.txt");
+    let bytes: Vec<u8> = std::iter::once(0xFEFF).chain(text.encode_utf16()).flat_map(u16::to_be_bytes).collect();
+    std::fs::write(&utf16, bytes).unwrap();
+    let (stdout, stderr, success) = flatten(utf16.to_str().unwrap());
+    assert!(success, "{}", stderr);
+    assert_eq!(cell(&stdout, "Knight", "Archer"), "250");
+
+    let windows1252 = scratch_path("overrides_windows1252.txt");
+    // Every character is below U+0100, where Windows-1252 agrees with it.
+    std::fs::write(&windows1252, text.chars().map(|c| c as u8).collect::<Vec<u8>>()).unwrap();
+    let (stdout, stderr, success) = flatten(windows1252.to_str().unwrap());
+    assert!(success, "{}", stderr);
+    assert!(stderr.contains("is not valid UTF-8, reading it as Windows-1252"), "{}", stderr);
+    assert_eq!(cell(&stdout, "Knight", "Archer"), "250");
+}
+
+#[test]
+fn invalid_rules_are_reported_with_their_line() {
+    let cases = [
+        ("Knight Pikeman: set 50", "Expected \"<attacker> vs <target>: <operation>\""),
+        ("Knight vs Pikeman set 50", "Expected \"<attacker> vs <target>: <operation>\""),
+        ("Knight vs Pikeman: double", "Unknown override operation \"double\""),
+        ("Knight vs Pikeman: set 50 60", "Wrong number of arguments for \"set\""),
+        ("Knight vs Pikeman: scale half", "Failed to parse \"half\""),
+        ("Knight vs Pikeman: clamp 90 80", "The clamp minimum must not exceed its maximum"),
+        ("Knight vs Pikeman:", "Missing override operation"),
+        ("[] vs Pikeman: set 50", "in objmask expression \"\""),
+        ("[M & ] vs Pikeman: set 50", "in objmask expression \"M & \""),
+        ("[M & Q9] vs Pikeman: set 50", "in objmask expression \"M & Q9\""),
+    ];
+    for (i, (rule, expected)) in cases.iter().enumerate() {
+        let overrides = scratch_file(&format!("overrides_invalid_{}.txt", i), &format!("# Invalid\n{}\n", rule));
+        let (_, stderr, success) = flatten(overrides.to_str().unwrap());
+        assert!(!success, "{}", rule);
+        assert!(stderr.contains(expected), "{} not in {}", expected, stderr);
+        assert!(stderr.contains(&format!("on line 2 of \"{}\"", overrides.display())), "{}", stderr);
+    }
+}
@@ -0,0 +1,81 @@
+mod common;
+
+use common::{cell, fixture, output, scratch_path, unit_rules};
+
+/// Flatten the patch fixture with `patches` applied in order, returning its
+/// standard output and standard error.
+fn flatten(patches: &[&str]) -> (String, String) {
+    let unit_rules = unit_rules();
+    let balance = fixture("patch/balance.xml");
+    let mut args = vec!["--unitrules", &unit_rules];
+    for patch in patches {
+        args.extend_from_slice(&["--patch", patch]);
+    }
+    args.push(balance.to_str().unwrap());
+
+    let result = output(&args);
+    assert!(result.status.success(), "{}", String::from_utf8_lossy(&result.stderr));
+    (String::from_utf8(result.stdout).unwrap(), String::from_utf8(result.stderr).unwrap())
+}
+
+/// Without patches, the fixture flattens to Knight vs Pikeman 50, Knight vs
+/// Archer 300, Pikeman vs Knight 200, Archer vs Knight 150 and Archer vs
+/// Pikeman 120, from its Archer entry and ARCHERY vs MELEE.
+#[test]
+fn every_operation_is_applied() {
+    let first = fixture("patch/first.xml");
+    let (stdout, stderr) = flatten(&[first.to_str().unwrap()]);
+
+    assert_eq!(cell(&stdout, "Knight", "Pikeman"), "60");
+    // MOUNTED vs FOOT_ARCHER and the new MELEE vs ARCHERY.
+    assert_eq!(cell(&stdout, "Knight", "Archer"), "390");
+    assert_eq!(cell(&stdout, "Pikeman", "Archer"), "130");
+    assert_eq!(cell(&stdout, "Pikeman", "Knight"), "300");
+    assert_eq!(cell(&stdout, "Archer", "Knight"), "100");
+    assert_eq!(cell(&stdout, "Archer", "Pikeman"), "100");
+    assert!(!stderr.contains("Warning"), "{}", stderr);
+}
+
+#[test]
+fn cells_changed_by_several_patches_are_warned_about() {
+    let first = fixture("patch/first.xml");
+    let second = fixture("patch/second.xml");
+    let (stdout, stderr) = flatten(&[first.to_str().unwrap(), second.to_str().unwrap()]);
+
+    assert_eq!(cell(&stdout, "Knight", "Pikeman"), "120");
+    let warnings: Vec<&str> = stderr.lines().filter(|line| line.starts_with("Warning: ")).collect();
+    assert_eq!(warnings, [format!("Warning: Flag_M_OBJMASK_MOUNTED vs Flag_5_OBJMASK_PIKE is changed by multiple \
+                                   patches: {}, {}", first.display(), second.display())]);
+}
+
+/// Patches are decoded like balance files.
+#[test]
+fn patches_can_be_in_other_encodings() {
+    let first = fixture("patch/first.xml");
+    let (expected, _) = flatten(&[first.to_str().unwrap()]);
+    let text = std::fs::read_to_string(&first).unwrap();
+
+    let utf16 = scratch_path("patch_utf16le.xml");
+    let bytes: Vec<u8> = std::iter::once(0xFEFF).chain(text.encode_utf16()).flat_map(u16::to_le_bytes).collect();
+    std::fs::write(&utf16, bytes).unwrap();
+    let (stdout, _) = flatten(&[utf16.to_str().unwrap()]);
+    assert_eq!(cell(&stdout, "Knight", "Archer"), cell(&expected, "Knight", "Archer"));
+    assert_eq!(cell(&stdout, "Archer", "Pikeman"), cell(&expected, "Archer", "Pikeman"));
+
+    let windows1252 = scratch_path("patch_windows1252.xml");
+    let bytes = [b"<PATCH>\n  <!-- R\xe9glage -->".as_ref(), text.strip_prefix("<PATCH>").unwrap().as_bytes()].concat();
+    std::fs::write(&windows1252, bytes).unwrap();
+    let (stdout, stderr) = flatten(&[windows1252.to_str().unwrap()]);
+    assert!(stderr.contains("is not valid UTF-8, reading it as Windows-1252"), "{}", stderr);
+    assert_eq!(cell(&stdout, "Knight", "Archer"), cell(&expected, "Knight", "Archer"));
+}
+
+#[test]
+fn missing_patches_are_reported() {
+    let missing = scratch_path("patch_missing.xml");
+    let result = output(&["--unitrules", &unit_rules(), "--patch", missing.to_str().unwrap(),
+                          fixture("patch/balance.xml").to_str().unwrap()]);
+    assert!(!result.status.success());
+    let stderr = String::from_utf8(result.stderr).unwrap();
+    assert!(stderr.contains(&format!("Failed to open patch \"{}\"", missing.display())), "{}", stderr);
+}
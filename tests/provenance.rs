@@ -0,0 +1,88 @@
+mod common;
+
+use std::path::Path;
+
+use common::{fixture, output, run, scratch_file, unit_rules};
+
+fn path(path: &Path) -> &str {
+    path.to_str().unwrap()
+}
+
+/// Run `verify` on `generated`, returning its standard error and whether
+/// it succeeded.
+fn verify(generated: &Path, balance: &Path) -> (String, bool) {
+    let result = output(&["verify", "--unitrules", &unit_rules(), path(generated), path(balance)]);
+    (String::from_utf8(result.stderr).unwrap(), result.status.success())
+}
+
+#[test]
+fn recorded_options_round_trip() {
+    let balance = fixture("provenance/balance.xml");
+    let patch = fixture("provenance/patch.xml");
+    let overrides = fixture("provenance/overrides.txt");
+    let unit_rules = unit_rules();
+    let generated = run(&[
+        "--unitrules", &unit_rules, "--table", "land units", "--combine", "add", "--indent", "tab",
+        "--attribute-per-line", "--crlf", "--patch", path(&patch), "--overrides", path(&overrides), path(&balance),
+    ]);
+
+    let options = generated.lines().find(|line| line.contains("Options: ")).unwrap();
+    assert_eq!(options.trim(), format!("Options: table=land%20units combine=add indent=tab attribute-per-line crlf \
+                                        patch={} overrides={}", path(&patch), path(&overrides)));
+    assert!(generated.contains(&format!("patch {} SHA-256: ", path(&patch))), "{}", generated);
+    assert!(generated.contains(&format!("overrides {} SHA-256: ", path(&overrides))), "{}", generated);
+
+    let generated = scratch_file("provenance_round_trip.xml", &generated);
+    let (stderr, success) = verify(&generated, &balance);
+    assert!(success, "{}", stderr);
+    assert!(stderr.contains("matches the output regenerated from its recorded inputs"), "{}", stderr);
+}
+
+#[test]
+fn verify_names_the_changed_input() {
+    let balance = scratch_file("provenance_changed_balance.xml",
+                               &std::fs::read_to_string(fixture("provenance/balance.xml")).unwrap());
+    let patch = scratch_file("provenance_changed_patch.xml",
+                             &std::fs::read_to_string(fixture("provenance/patch.xml")).unwrap());
+    let generated = run(&["--unitrules", &unit_rules(), "--patch", path(&patch), path(&balance)]);
+    let generated = scratch_file("provenance_changed.xml", &generated);
+
+    let (stderr, success) = verify(&generated, &balance);
+    assert!(success, "{}", stderr);
+
+    std::fs::write(&patch, "<PATCH/>").unwrap();
+    let (stderr, success) = verify(&generated, &balance);
+    assert!(!success);
+    assert!(stderr.contains(&format!("The patch {} is not the one that", path(&patch))), "{}", stderr);
+
+    std::fs::write(&balance, "<ROOT/>").unwrap();
+    let (stderr, success) = verify(&generated, &balance);
+    assert!(!success);
+    assert!(stderr.contains("The balance.xml is not the one that"), "{}", stderr);
+}
+
+#[test]
+fn verify_finds_edited_output() {
+    let balance = fixture("provenance/balance.xml");
+    let generated = run(&["--unitrules", &unit_rules(), path(&balance)]);
+
+    let edited = generated.replacen("Pikeman=\"60\"", "Pikeman=\"65\"", 1);
+    assert_ne!(edited, generated);
+    let line = generated.lines().position(|line| line.contains("Pikeman=\"60\"")).unwrap() + 1;
+    let edited = scratch_file("provenance_edited.xml", &edited);
+
+    let (stderr, success) = verify(&edited, &balance);
+    assert!(!success);
+    assert!(stderr.contains(&format!("differs from the output regenerated from its recorded inputs, from line {}",
+                                     line)), "{}", stderr);
+}
+
+/// Only files written by the main run and `update` have a header to verify.
+#[test]
+fn verify_requires_a_header() {
+    let balance = fixture("provenance/balance.xml");
+    let (stderr, success) = verify(&balance, &balance);
+    assert!(!success);
+    assert!(stderr.contains(&format!("{} has no provenance header to verify it against. Only the main run and \
+                                      update write one", path(&balance))), "{}", stderr);
+}
@@ -0,0 +1,125 @@
+mod common;
+
+use common::{fixture, output, run};
+
+/// The reports fixture has Knight (MW) at 300 against the foot archers
+/// Archer and Crossbowman (FKR), which are at 200 against Pikeman (FW5),
+/// which is at 200 against Knight. Knight vs Pikeman is 80 directly and 50
+/// through MOUNTED vs PIKE.
+fn balance() -> String {
+    fixture("reports/balance.xml").to_str().unwrap().to_owned()
+}
+
+fn lines(output: &str) -> Vec<&str> {
+    output.lines().map(str::trim_end).collect()
+}
+
+#[test]
+fn counters_lists_the_top_matchups() {
+    let output = run(&["counters", "--top", "1", &balance(), "Knight"]);
+    let lines = lines(&output);
+    assert_eq!(lines[2], "Archer  300       Flag_M_OBJMASK_MOUNTED vs Flag_K_OBJMASK_FOOT_ARCHER: 300");
+    assert_eq!(lines[6],
+               "Pikeman  40        Knight vs Pikeman: 80, Flag_M_OBJMASK_MOUNTED vs Flag_5_OBJMASK_PIKE: 50");
+    assert_eq!(lines[10], "Pikeman   200       Flag_5_OBJMASK_PIKE vs Flag_M_OBJMASK_MOUNTED: 200");
+    assert_eq!(lines.len(), 15, "{}", output);
+}
+
+#[test]
+fn counters_takes_a_combine_mode() {
+    let output = run(&["counters", "--combine", "add", &balance(), "Knight"]);
+    assert!(output.contains("Pikeman      30 "), "{}", output);
+}
+
+#[test]
+fn graph_draws_counter_loops() {
+    let output = run(&["graph", "--cycles", "--threshold", "250", &balance()]);
+    assert!(output.contains("\"Knight\" -> \"Archer\" [weight = 300, label = \"300\"];"), "{}", output);
+    assert!(!output.contains("\"Pikeman\" -> \"Knight\""), "{}", output);
+
+    let output = run(&["graph", "--cycles", &balance()]);
+    assert!(output.contains("\"Pikeman\" -> \"Knight\" [weight = 200, label = \"200\", color = red];"), "{}",
+            output);
+}
+
+#[test]
+fn classes_groups_identical_units() {
+    let output = run(&["classes", &balance()]);
+    assert_eq!(lines(&output), [
+        "Units sharing an OBJ_MASK:",
+        "    FKR: Archer, Crossbowman",
+        "",
+        "Units with identical flattened rows and columns:",
+        "    Archer (FKR), Crossbowman (FKR)",
+    ]);
+}
+
+#[test]
+fn anomalies_fails_in_ci_mode() {
+    let report = run(&["anomalies", "--max", "250", &balance()]);
+    assert_eq!(lines(&report), [
+        "Knight vs Archer: 300 (above 250)",
+        "    Flag_M_OBJMASK_MOUNTED vs Flag_K_OBJMASK_FOOT_ARCHER: 300",
+        "Knight vs Crossbowman: 300 (above 250)",
+        "    Flag_M_OBJMASK_MOUNTED vs Flag_K_OBJMASK_FOOT_ARCHER: 300",
+    ]);
+
+    let result = output(&["anomalies", "--max", "250", "--ci", &balance()]);
+    assert!(!result.status.success());
+    assert!(String::from_utf8_lossy(&result.stderr).contains("Error: 2 anomalous cells found"));
+
+    run(&["anomalies", "--ci", &balance()]);
+}
+
+#[test]
+fn impact_summarises_each_flag() {
+    let output = run(&["impact", "--top", "1", &balance()]);
+    let lines = lines(&output);
+    assert_eq!(lines[1], "Flag_M_OBJMASK_MOUNTED      4      Knight vs Archer (-200)   Knight vs Pikeman (+100%)");
+    assert_eq!(lines[4], "Flag_R_OBJMASK_ARCHERY      2      Archer vs Pikeman (-100)  Archer vs Pikeman (-50%)");
+    assert_eq!(&lines[6..10], ["Flag_M_OBJMASK_MOUNTED", "", "Attacker  Pairs  Total error", "Knight    3      440"]);
+}
+
+#[test]
+fn evaluate_compares_intended_and_engine_modifiers() {
+    let output = run(&["evaluate", "--combine", "add", &balance(), "Knight", "Archer", "Knight", "Pikeman"]);
+    assert_eq!(lines(&output), [
+        "Attacker  Target   Intended  Engine  Difference",
+        "Knight    Archer   300       100     -200",
+        "Knight    Pikeman  30        80      50",
+    ]);
+}
+
+/// Every report reads unitrules.xml from `--unitrules`, which is required
+/// when the balance file is read from standard input.
+#[test]
+fn reports_share_their_options() {
+    let unit_rules = fixture("reports/unitrules.xml");
+    let unit_rules = unit_rules.to_str().unwrap();
+    let balance = balance();
+
+    let commands: [&[&str]; 6] = [
+        &["counters", "-", "Knight"],
+        &["graph", "-"],
+        &["classes", "-"],
+        &["anomalies", "-"],
+        &["impact", "-"],
+        &["evaluate", "--all", "-"],
+    ];
+    for args in &commands {
+        let result = output(args);
+        assert!(String::from_utf8_lossy(&result.stderr).contains("--unitrules is required"), "{:?}", args);
+
+        let result = output(&[args[0], "--unitrules"]);
+        assert!(String::from_utf8_lossy(&result.stderr).contains("Missing value for option \"--unitrules\""),
+                "{:?}", args);
+    }
+
+    run(&["classes", "--unitrules", unit_rules, &balance]);
+
+    let result = output(&["graph", "--top", "1", &balance]);
+    assert!(String::from_utf8_lossy(&result.stderr).contains("Unknown option \"--top\""));
+
+    let result = output(&["impact", "--combine", "add", &balance]);
+    assert!(String::from_utf8_lossy(&result.stderr).contains("Unknown option \"--combine\""));
+}
@@ -0,0 +1,50 @@
+mod common;
+
+#[cfg(feature = "serde")]
+use common::{cell, fixture, run, scratch_file};
+
+/// Flag character codes are expanded in `[units]` as they are in `[flags]`.
+#[cfg(feature = "serde")]
+#[test]
+fn units_rules_expand_flag_codes() {
+    let source = fixture("source/balance.toml");
+    let unit_rules = fixture("source/unitrules.xml");
+    let output = run(&["compile", source.to_str().unwrap(), unit_rules.to_str().unwrap()]);
+
+    assert!(!output.contains("<ENTRY name=\"M\""), "unexpanded flag code in {}", output);
+    assert_eq!(cell(&output, "Hoplite", "Knight"), "120");
+    assert_eq!(cell(&output, "Knight", "Archer"), "150");
+    assert_eq!(cell(&output, "HorseArcher", "Archer"), "150");
+    assert_eq!(cell(&output, "Knight", "Hoplite"), "50");
+}
+
+/// A decompiled balance file compiles back to the same flattened cells.
+#[cfg(feature = "serde")]
+#[test]
+fn decompiled_files_compile_to_the_same_cells() {
+    let source = fixture("source/balance.toml");
+    let unit_rules = fixture("source/unitrules.xml");
+    let unit_rules = unit_rules.to_str().unwrap();
+    let compiled = run(&["compile", source.to_str().unwrap(), unit_rules]);
+
+    let flattened = scratch_file("source_flattened.xml", &compiled);
+    let decompiled = run(&["decompile", flattened.to_str().unwrap()]);
+    let decompiled = scratch_file("source_decompiled.toml", &decompiled);
+    let recompiled = run(&["compile", decompiled.to_str().unwrap(), unit_rules]);
+
+    for (attacker, target) in [("Hoplite", "Knight"), ("Knight", "Archer"), ("HorseArcher", "Archer"),
+                               ("Knight", "Hoplite"), ("Archer", "Archer")] {
+        assert_eq!(cell(&recompiled, attacker, target), cell(&compiled, attacker, target));
+    }
+}
+
+#[cfg(not(feature = "serde"))]
+#[test]
+fn source_files_require_the_serde_feature() {
+    for command in ["compile", "decompile"] {
+        let result = common::output(&[command, "balance.toml", "unitrules.xml"]);
+        assert!(!result.status.success());
+        let stderr = String::from_utf8(result.stderr).unwrap();
+        assert!(stderr.contains(&format!("The {} command requires the \"serde\" feature", command)), "{}", stderr);
+    }
+}
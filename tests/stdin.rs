@@ -0,0 +1,53 @@
+mod common;
+
+use common::{fixture, run, run_with_stdin, unit_rules};
+
+/// A balance file of `-` is read from standard input, giving the same
+/// output as reading it from its path.
+#[test]
+fn balance_files_can_be_piped_in() {
+    let unit_rules = unit_rules();
+    let balance = fixture("overrides/balance.xml");
+    let contents = std::fs::read(&balance).unwrap();
+
+    let expected = run(&["--unitrules", &unit_rules, balance.to_str().unwrap()]);
+    assert_eq!(run_with_stdin(&["--unitrules", &unit_rules, "-"], &contents), expected);
+}
+
+#[test]
+fn reports_can_read_standard_input() {
+    let unit_rules = fixture("reports/unitrules.xml");
+    let unit_rules = unit_rules.to_str().unwrap();
+    let balance = fixture("reports/balance.xml");
+    let contents = std::fs::read(&balance).unwrap();
+
+    let expected = run(&["counters", "--unitrules", unit_rules, balance.to_str().unwrap(), "Knight"]);
+    assert_eq!(run_with_stdin(&["counters", "--unitrules", unit_rules, "-", "Knight"], &contents), expected);
+}
+
+/// The part of a provenance header line after `field`.
+fn header_field<'a>(balance_xml: &'a str, field: &str) -> &'a str {
+    balance_xml.lines()
+        .find_map(|line| line.trim().strip_prefix(field))
+        .unwrap_or_else(|| panic!("no {} in {}", field, balance_xml))
+}
+
+/// Patches and overrides files may be piped in instead, and are recorded
+/// in the provenance header as they were read.
+#[test]
+fn patches_and_overrides_can_be_piped_in() {
+    let unit_rules = unit_rules();
+    let balance = fixture("overrides/balance.xml");
+    let balance = balance.to_str().unwrap();
+
+    for (option, kind, file) in [("--patch", "patch", fixture("patch/first.xml")),
+                                 ("--overrides", "overrides", fixture("overrides/overrides.txt"))] {
+        let contents = std::fs::read(&file).unwrap();
+        let expected = run(&["--unitrules", &unit_rules, option, file.to_str().unwrap(), balance]);
+        let stdout = run_with_stdin(&["--unitrules", &unit_rules, option, "-", balance], &contents);
+
+        assert_eq!(stdout.split_once("-->").unwrap().1, expected.split_once("-->").unwrap().1);
+        assert_eq!(header_field(&stdout, &format!("{} - ", kind)),
+                   header_field(&expected, &format!("{} {} ", kind, file.display())));
+    }
+}
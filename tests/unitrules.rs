@@ -0,0 +1,63 @@
+mod common;
+
+use common::{fixture, output};
+
+/// Parse `file` from the unitrules fixture, returning whether it could be
+/// read, each unit with its OBJ_MASK and the standard error. Every unit is
+/// reported by `update` as added to an empty unitrules.xml.
+fn parse(file: &str) -> (bool, Vec<String>, String) {
+    let empty = fixture("unitrules/empty.xml");
+    let empty = empty.to_str().unwrap();
+    let unit_rules = fixture(&format!("unitrules/{}", file));
+    let result = output(&["update", empty, unit_rules.to_str().unwrap(), empty, empty]);
+
+    let stderr = String::from_utf8(result.stderr).unwrap();
+    let units = stderr.lines()
+        .filter_map(|line| line.strip_prefix("Added "))
+        .map(str::to_owned)
+        .collect();
+    (result.status.success(), units, stderr)
+}
+
+/// Only NAME and OBJ_MASK elements directly inside a UNIT are read, and
+/// UNIT elements are found at any depth.
+#[test]
+fn nested_elements_are_ignored() {
+    let (success, units, stderr) = parse("nested.xml");
+    assert!(success, "{}", stderr);
+    assert_eq!(units, ["Knight (MW)", "Pikeman (FW5)"]);
+}
+
+/// Text is trimmed and may be split by CDATA sections and entities. Spaces
+/// in names become underscores and apostrophes are dropped.
+#[test]
+fn text_is_trimmed_and_unescaped() {
+    let (success, units, stderr) = parse("text.xml");
+    assert!(success, "{}", stderr);
+    assert_eq!(units, ["Heavy_Knight (MWY)", "Knights_<Squire> (M4)", "Sapper_&_Miner (FWX)"]);
+    assert!(!stderr.contains("unknown OBJ_MASK flag"), "{}", stderr);
+}
+
+#[test]
+fn units_can_be_given_as_attributes() {
+    let (success, units, stderr) = parse("attributes.xml");
+    assert!(success, "{}", stderr);
+    assert_eq!(units, ["Hussar (M4)", "Archer (FKR)", "Slinger ()"]);
+}
+
+#[test]
+fn units_without_a_name_are_skipped() {
+    let (success, units, stderr) = parse("invalid.xml");
+    assert!(success, "{}", stderr);
+    assert_eq!(units, ["Knight (M)"]);
+    assert_eq!(stderr.matches("Warning: UNIT element without a NAME skipped").count(), 2, "{}", stderr);
+    assert!(stderr.contains("Warning: unknown OBJ_MASK flag found 'w'"), "{}", stderr);
+}
+
+#[test]
+fn malformed_files_are_rejected() {
+    let (success, units, stderr) = parse("malformed.xml");
+    assert!(!success);
+    assert!(units.is_empty(), "{:?}", units);
+    assert!(stderr.contains("Error: Failed to read unitrules.xml: "), "{}", stderr);
+}
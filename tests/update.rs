@@ -0,0 +1,96 @@
+mod common;
+
+use std::path::Path;
+
+use common::{cell, fixture, output, run, scratch_file, unit_rules};
+
+fn path(path: &Path) -> &str {
+    path.to_str().unwrap()
+}
+
+/// Flatten the land table of the update fixture with the old unitrules.xml,
+/// which has Archer as FKR.
+fn flatten(args: &[&str]) -> String {
+    let unit_rules = unit_rules();
+    let source = fixture("update/source.xml");
+    let mut all_args = vec!["--unitrules", &unit_rules, "--table", "land"];
+    all_args.extend_from_slice(args);
+    all_args.push(path(&source));
+    run(&all_args)
+}
+
+/// Update `flattened` for the new unitrules.xml, where Archer is MKR,
+/// returning its standard output and standard error.
+fn update(args: &[&str], flattened: &Path) -> (String, String) {
+    let unit_rules = unit_rules();
+    let new_unit_rules = fixture("update/new_unitrules.xml");
+    let source = fixture("update/source.xml");
+    let mut all_args = vec!["update"];
+    all_args.extend_from_slice(args);
+    all_args.extend_from_slice(&[&unit_rules, path(&new_unit_rules), path(&source), path(flattened)]);
+
+    let result = output(&all_args);
+    assert!(result.status.success(), "{}", String::from_utf8_lossy(&result.stderr));
+    (String::from_utf8(result.stdout).unwrap(), String::from_utf8(result.stderr).unwrap())
+}
+
+/// Only the cells of the changed unit are recalculated, with the options
+/// recorded in the flattened file. Knight vs Pikeman was edited by hand
+/// and is kept.
+#[test]
+fn changed_units_are_recalculated() {
+    let flattened = flatten(&["--combine", "add"]);
+    assert_eq!(cell(&flattened, "Pikeman", "Archer"), "150");
+    assert_eq!(cell(&flattened, "Archer", "Pikeman"), "100");
+    let edited = flattened.replacen("Pikeman=\"50\"", "Pikeman=\"55\"", 1);
+    assert_eq!(cell(&edited, "Knight", "Pikeman"), "55");
+    let edited = scratch_file("update_edited.xml", &edited);
+
+    let (stdout, stderr) = update(&[], &edited);
+    assert!(stderr.contains("Changed Archer: OBJ_MASK FKR -> KMR"), "{}", stderr);
+    assert!(stderr.contains("Knight vs Pikeman is 55 rather than 50"), "{}", stderr);
+
+    // PIKE vs MOUNTED and MELEE vs ARCHERY added together.
+    assert_eq!(cell(&stdout, "Pikeman", "Archer"), "250");
+    assert_eq!(cell(&stdout, "Archer", "Pikeman"), "50");
+    assert_eq!(cell(&stdout, "Knight", "Pikeman"), "55");
+    assert_eq!(cell(&stdout, "Pikeman", "Knight"), "200");
+
+    assert!(stdout.contains("<!-- Land units -->"), "{}", stdout);
+    assert!(stdout.contains("<ENTRY name=\"Galley\" Galley=\"90\"/>"), "{}", stdout);
+    assert!(stdout.contains("Options: table=land combine=add"), "{}", stdout);
+}
+
+/// The updated file records the source balance file and the new
+/// unitrules.xml, so `verify` can regenerate it.
+#[test]
+fn updated_files_can_be_verified() {
+    let flattened = scratch_file("update_flattened.xml", &flatten(&["--combine", "add", "--crlf"]));
+    let (stdout, _) = update(&[], &flattened);
+    assert!(stdout.contains("\r\n"), "{}", stdout);
+    let updated = scratch_file("update_updated.xml", &stdout);
+
+    let new_unit_rules = fixture("update/new_unitrules.xml");
+    let source = fixture("update/source.xml");
+    let result = output(&["verify", "--unitrules", path(&new_unit_rules), path(&updated), path(&source)]);
+    assert!(result.status.success(), "{}", String::from_utf8_lossy(&result.stderr));
+}
+
+/// Without a provenance header, the options are taken from the command
+/// line.
+#[test]
+fn options_can_be_given_for_files_without_a_header() {
+    let flattened = flatten(&["--combine", "add"]);
+    let without_header = flattened.replacen("<!--", "<!-- Edited", 1);
+    let without_header = scratch_file("update_without_header.xml", &without_header);
+
+    let (stdout, stderr) = update(&["--table", "land"], &without_header);
+    assert!(stderr.contains("has no provenance header"), "{}", stderr);
+    assert_eq!(cell(&stdout, "Pikeman", "Archer"), "300");
+
+    let (stdout, _) = update(&["--table", "land", "--combine", "add"], &without_header);
+    assert_eq!(cell(&stdout, "Pikeman", "Archer"), "250");
+
+    let result = output(&["update", "--only", "Archer", "a.xml", "b.xml", "c.xml", "d.xml"]);
+    assert!(String::from_utf8_lossy(&result.stderr).contains("--only cannot be used with update"));
+}
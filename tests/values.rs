@@ -0,0 +1,80 @@
+mod common;
+
+use common::{cell, fixture, output, run, scratch_file, unit_rules};
+
+/// Flatten `file` from the values fixture, returning its standard output,
+/// standard error and whether it succeeded.
+fn flatten(file: &str) -> (String, String, bool) {
+    let balance = fixture(&format!("values/{}", file));
+    let result = output(&["--unitrules", &unit_rules(), balance.to_str().unwrap()]);
+    (String::from_utf8(result.stdout).unwrap(), String::from_utf8(result.stderr).unwrap(), result.status.success())
+}
+
+#[test]
+fn lenient_numbers_are_read_with_a_warning() {
+    let (stdout, stderr, success) = flatten("balance.xml");
+    assert!(success, "{}", stderr);
+
+    assert_eq!(cell(&stdout, "Knight", "Pikeman"), "120");
+    assert_eq!(cell(&stdout, "Knight", "Archer"), "120");
+    assert_eq!(cell(&stdout, "Pikeman", "Knight"), "120");
+    for warning in &[
+        "Warning: Knight vs Pikeman value \"+120%\" read as 120",
+        "Warning: Knight vs Archer value \" 120 \" read as 120",
+        "Warning: Pikeman vs Knight value \"1.2e2\" read as 120",
+    ] {
+        assert!(stderr.contains(warning), "{} not in {}", warning, stderr);
+    }
+}
+
+/// Values that are not numbers are written back out unchanged, and
+/// unchanged cells keep their original form.
+#[test]
+fn other_values_are_kept() {
+    let (stdout, stderr, success) = flatten("balance.xml");
+    assert!(success, "{}", stderr);
+
+    assert_eq!(cell(&stdout, "Knight", "note"), "see the design doc");
+    assert_eq!(cell(&stdout, "Pikeman", "Archer"), "100.0");
+    assert_eq!(cell(&stdout, "Archer", "Knight"), "87.5");
+}
+
+#[test]
+fn every_invalid_value_is_reported() {
+    let (stdout, stderr, success) = flatten("invalid.xml");
+    assert!(!success);
+    assert!(stdout.is_empty(), "{}", stdout);
+    assert!(stderr.contains("Error: Found 4 invalid values in balance.xml:\n\
+                             \x20   Knight vs Pikeman: \"\" is empty\n\
+                             \x20   Knight vs Archer: \"12..5\" is not a valid number\n\
+                             \x20   Flag_M_OBJMASK_MOUNTED vs Flag_5_OBJMASK_PIKE: \"half\" is not a number\n\
+                             \x20   Pikeman vs Knight: \"1e40\" is not a valid number\n"), "{}", stderr);
+}
+
+/// `fmt` writes values in their shortest form unless told to keep them.
+#[test]
+fn fmt_canonicalises_values() {
+    let balance = fixture("values/balance.xml");
+    let balance = balance.to_str().unwrap();
+
+    let formatted = run(&["fmt", balance]);
+    assert_eq!(cell(&formatted, "Knight", "Pikeman"), "120");
+    assert_eq!(cell(&formatted, "Knight", "Archer"), "120");
+    assert_eq!(cell(&formatted, "Pikeman", "Knight"), "120");
+    assert_eq!(cell(&formatted, "Pikeman", "Archer"), "100");
+    assert_eq!(cell(&formatted, "Archer", "Knight"), "87.5");
+    assert_eq!(cell(&formatted, "Knight", "note"), "see the design doc");
+
+    let result = output(&["fmt", "--check", balance]);
+    assert!(!result.status.success());
+    let formatted = scratch_file("values_formatted.xml", &formatted);
+    run(&["fmt", "--check", formatted.to_str().unwrap()]);
+
+    // Values that are only read with a warning are still rewritten.
+    let kept = run(&["fmt", "--keep-values", balance]);
+    assert_eq!(cell(&kept, "Pikeman", "Archer"), "100.0");
+    assert_eq!(cell(&kept, "Knight", "Pikeman"), "120");
+    let kept = scratch_file("values_kept.xml", &kept);
+    run(&["fmt", "--check", "--keep-values", kept.to_str().unwrap()]);
+    assert!(!output(&["fmt", "--check", kept.to_str().unwrap()]).status.success());
+}